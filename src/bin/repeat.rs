@@ -78,5 +78,5 @@ fn main() {
     }
 
     println!("Reload repeat command");
-    cmd!("cargo run --bin repeat").command.exec();
+    cmd!("cargo run --bin repeat").command().exec();
 }
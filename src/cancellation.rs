@@ -0,0 +1,87 @@
+// Copyright 2017 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use local_shell::current_shell;
+use result::ShellError;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Flag telling Rust code running on a `LocalShell` to stop.
+///
+/// Each `LocalShell` has a token which is cancelled when the shell is
+/// signaled. Clones share the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<(Mutex<bool>, Condvar)>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Cancels the token and wakes up threads waiting for it.
+    pub fn cancel(&self) {
+        let (cancelled, condvar) = &*self.0;
+        *cancelled.lock().unwrap() = true;
+        condvar.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *(self.0).0.lock().unwrap()
+    }
+
+    /// Returns `ShellError::Cancelled` if the token is cancelled.
+    pub fn check(&self) -> Result<(), ShellError> {
+        if self.is_cancelled() {
+            Err(ShellError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Blocks until the token is cancelled or `timeout` elapses, and returns
+    /// whether it is cancelled.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let (cancelled, condvar) = &*self.0;
+        let mut cancelled = cancelled.lock().unwrap();
+        while !*cancelled {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            cancelled = condvar.wait_timeout(cancelled, deadline - now)
+                .unwrap().0;
+        }
+        *cancelled
+    }
+}
+
+/// Returns the token of the current `LocalShell`.
+pub fn cancellation_token() -> CancellationToken {
+    current_shell().lock().unwrap().cancellation_token()
+}
+
+/// Returns whether the current `LocalShell` has been signaled.
+pub fn is_cancelled() -> bool {
+    cancellation_token().is_cancelled()
+}
+
+/// Returns `ShellError::Cancelled` if the current `LocalShell` has been
+/// signaled, so loops between commands can stop with `?`.
+pub fn check_cancelled() -> Result<(), ShellError> {
+    cancellation_token().check()
+}
@@ -0,0 +1,140 @@
+// Copyright 2017 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use errno::errno;
+use libc;
+use result::ShellError;
+use result::check_errno;
+use std::io::Read;
+use std::os::unix::io::AsRawFd;
+use std::process::ChildStderr;
+use std::process::ChildStdout;
+use std::thread::JoinHandle;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Bytes read from stdout or stderr at once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub stream: Stream,
+    /// Time from starting capture until the chunk is read.
+    pub elapsed: Duration,
+    pub data: Vec<u8>,
+}
+
+/// stdout and stderr of a command recorded in the order they were read.
+///
+/// The two streams are read by polling both pipes, so the order between them
+/// is the order in which the current process observed the output.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Transcript {
+    pub chunks: Vec<Chunk>,
+}
+
+impl Transcript {
+    fn collect(&self, stream: Option<Stream>) -> Vec<u8> {
+        self.chunks.iter()
+            .filter(|chunk| match stream {
+                Some(stream) => stream == chunk.stream,
+                None => true,
+            })
+            .flat_map(|chunk| chunk.data.iter().cloned())
+            .collect()
+    }
+
+    /// Returns stdout and stderr interleaved in order.
+    pub fn combined(&self) -> Vec<u8> {
+        self.collect(None)
+    }
+
+    pub fn stdout(&self) -> Vec<u8> {
+        self.collect(Some(Stream::Stdout))
+    }
+
+    pub fn stderr(&self) -> Vec<u8> {
+        self.collect(Some(Stream::Stderr))
+    }
+
+    /// Returns the combined output as a string.
+    pub fn to_string_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.combined()).into_owned()
+    }
+}
+
+fn read_chunks(mut stdout: Option<ChildStdout>, mut stderr: Option<ChildStderr>)
+        -> Result<Transcript, ShellError> {
+    let started = Instant::now();
+    let mut transcript = Transcript::default();
+    let mut buffer = [0u8; 4096];
+    while stdout.is_some() || stderr.is_some() {
+        let mut fds = [
+            libc::pollfd {
+                fd: stdout.as_ref().map_or(-1, |s| s.as_raw_fd()),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: stderr.as_ref().map_or(-1, |s| s.as_raw_fd()),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        let result = unsafe {
+            libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1)
+        };
+        if result == -1 && errno().0 == libc::EINTR {
+            continue;
+        }
+        check_errno("poll", result)?;
+
+        if fds[0].revents != 0 {
+            let size = stdout.as_mut().unwrap().read(&mut buffer)?;
+            if size == 0 {
+                stdout = None;
+            } else {
+                transcript.chunks.push(Chunk {
+                    stream: Stream::Stdout,
+                    elapsed: started.elapsed(),
+                    data: buffer[..size].to_vec(),
+                });
+            }
+        }
+        if fds[1].revents != 0 {
+            let size = stderr.as_mut().unwrap().read(&mut buffer)?;
+            if size == 0 {
+                stderr = None;
+            } else {
+                transcript.chunks.push(Chunk {
+                    stream: Stream::Stderr,
+                    elapsed: started.elapsed(),
+                    data: buffer[..size].to_vec(),
+                });
+            }
+        }
+    }
+    Ok(transcript)
+}
+
+/// Starts a thread recording stdout and stderr until both are closed.
+pub fn spawn_capture(stdout: Option<ChildStdout>, stderr: Option<ChildStderr>)
+        -> JoinHandle<Result<Transcript, ShellError>> {
+    thread::spawn(move || read_chunks(stdout, stderr))
+}
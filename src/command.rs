@@ -12,11 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use dry_run::quote;
+use environment::var;
 use shell_command::ShellCommand;
 use nom::IResult;
 use std::process::Command;
-use std::env;
 use std::env::VarError;
+use std::ffi::OsStr;
 
 fn token_char(ch: char) -> bool {
     if ch.len_utf8() > 1 {
@@ -58,7 +60,7 @@ impl Token {
                     token += args.next().expect("Too many placeholders"),
                 TokenPart::EnvVariable(name) => {
                     debug!("Environment variable {}", name);
-                    token += &env::var(name)?
+                    token += &var(name)?
                 }
             }
         }
@@ -114,7 +116,8 @@ pub fn new_command(format: &str, args: &[&str])
     if vec.len() > 1 {
         command.args(&vec[1..]);
     }
-    let line = vec.join(" ");
+    let line = vec.iter().map(|word| quote(OsStr::new(word)))
+        .collect::<Vec<_>>().join(" ");
     Ok(ShellCommand::new(line, command))
 }
 
@@ -135,6 +138,7 @@ fn test_parse_cmd() {
 #[test]
 fn test_parse_cmd_env() {
     use env_logger;
+    use std::env;
     env_logger::init().unwrap();
     env::set_var("MY_VAR", "VALUE");
     let tokens = parse_cmd("echo $MY_VAR/dir", &[]).unwrap();
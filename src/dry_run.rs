@@ -0,0 +1,117 @@
+// Copyright 2017 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use local_shell::current_shell;
+use result::ShellResult;
+use result::ShellResultExt;
+use result::SuccessPredicate;
+use result::from_status_if;
+use std::ffi::OsStr;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::Mutex;
+
+/// Result reported by commands in dry-run mode instead of running them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DryRunResult {
+    /// Exit code of the command.
+    pub code: u8,
+    /// Output returned by `stdout_utf8()` and `lines()`.
+    pub stdout: String,
+}
+
+impl DryRunResult {
+    pub fn new(code: u8, stdout: &str) -> DryRunResult {
+        DryRunResult {
+            code,
+            stdout: stdout.to_string(),
+        }
+    }
+}
+
+lazy_static! {
+    static ref DRY_RUN: Mutex<Option<DryRunResult>> = Mutex::new(None);
+}
+
+/// Enables dry-run mode for every thread, or disables it if `result` is
+/// `None`.
+///
+/// Commands print the quoted command line to stderr and return `result`
+/// instead of running.
+pub fn set_dry_run_default(result: Option<DryRunResult>) {
+    *DRY_RUN.lock().unwrap() = result;
+}
+
+/// Enables dry-run mode for the current `LocalShell` and threads it starts
+/// later by `shell::spawn`. `None` restores the default set by
+/// `set_dry_run_default()`.
+pub fn set_dry_run(result: Option<DryRunResult>) {
+    current_shell().lock().unwrap().set_dry_run(result);
+}
+
+/// Returns the fake result if the current `LocalShell` is in dry-run mode.
+pub fn dry_run() -> Option<DryRunResult> {
+    let local = current_shell().lock().unwrap().dry_run();
+    local.or_else(|| DRY_RUN.lock().unwrap().clone())
+}
+
+/// Quotes `arg` for a POSIX shell if it contains special characters.
+pub fn quote(arg: &OsStr) -> String {
+    let arg = arg.to_string_lossy();
+    let safe = |ch: char| ch.is_ascii_alphanumeric() || "_@%+=:,./-".contains(ch);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        return arg.into_owned();
+    }
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Stands in for a process spawned by a command in dry-run mode.
+#[derive(Clone)]
+pub struct DryRunChild {
+    line: String,
+    result: DryRunResult,
+    success: Option<SuccessPredicate>,
+}
+
+impl DryRunChild {
+    pub fn new(line: String, result: DryRunResult,
+               success: Option<SuccessPredicate>) -> DryRunChild {
+        DryRunChild {
+            line,
+            result,
+            success,
+        }
+    }
+
+    pub fn stdout(&self) -> &str {
+        &self.result.stdout
+    }
+
+    pub fn wait(self) -> ShellResult {
+        let status = ExitStatus::from_raw((self.result.code as i32) << 8);
+        match self.success {
+            Some(ref success) =>
+                from_status_if(self.line, status, &**success),
+            None => ShellResult::from_status(self.line, status),
+        }
+    }
+}
+
+#[test]
+fn test_quote() {
+    assert_eq!(quote(OsStr::new("a/b.c")), "a/b.c");
+    assert_eq!(quote(OsStr::new("")), "''");
+    assert_eq!(quote(OsStr::new("a b")), "'a b'");
+    assert_eq!(quote(OsStr::new("it's")), "'it'\\''s'");
+}
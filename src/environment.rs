@@ -0,0 +1,102 @@
+// Copyright 2017 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use local_shell::current_shell;
+use result::ShellError;
+use std::env::VarError;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Returns the working directory of the current `LocalShell`.
+pub fn cwd() -> Result<PathBuf, ShellError> {
+    match current_shell().lock().unwrap().cwd() {
+        Some(cwd) => Ok(cwd),
+        None => Ok(env::current_dir()?),
+    }
+}
+
+/// Resolves `path` relative to the working directory of the current
+/// `LocalShell`.
+pub fn resolve_path<P: AsRef<Path>>(path: P) -> Result<PathBuf, ShellError> {
+    let path = path.as_ref();
+    if path.is_absolute() {
+        return Ok(path.to_path_buf());
+    }
+    Ok(cwd()?.join(path))
+}
+
+/// Changes the working directory of commands run on the current `LocalShell`.
+///
+/// Unlike `std::env::set_current_dir()`, other threads are not affected.
+pub fn cd<P: AsRef<Path>>(path: P) -> Result<(), ShellError> {
+    let path = resolve_path(path)?;
+    if !fs::metadata(&path)?.is_dir() {
+        return Err(ShellError::from(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Not a directory: {}", path.display()))));
+    }
+    debug!("cd {}", path.display());
+    current_shell().lock().unwrap().set_cwd(Some(path));
+    Ok(())
+}
+
+/// Restores the previous working directory of the current `LocalShell` when
+/// it is dropped.
+#[must_use]
+pub struct PushdGuard {
+    previous: Option<PathBuf>,
+}
+
+impl Drop for PushdGuard {
+    fn drop(&mut self) {
+        current_shell().lock().unwrap().set_cwd(self.previous.take());
+    }
+}
+
+/// Same as `cd()`, but returns a guard which does `popd` when it is dropped.
+pub fn pushd<P: AsRef<Path>>(path: P) -> Result<PushdGuard, ShellError> {
+    let previous = current_shell().lock().unwrap().cwd();
+    cd(path)?;
+    Ok(PushdGuard { previous })
+}
+
+/// Sets an environment variable for commands run on the current `LocalShell`.
+///
+/// Unlike `std::env::set_var()`, other threads are not affected.
+pub fn export<K, V>(key: K, value: V) where K: AsRef<OsStr>, V: AsRef<OsStr> {
+    current_shell().lock().unwrap().set_var(
+        key.as_ref().to_os_string(), Some(value.as_ref().to_os_string()));
+}
+
+/// Removes an environment variable for commands run on the current
+/// `LocalShell`.
+pub fn unset<K: AsRef<OsStr>>(key: K) {
+    current_shell().lock().unwrap().set_var(key.as_ref().to_os_string(), None);
+}
+
+/// Returns an environment variable as seen by commands run on the current
+/// `LocalShell`.
+pub fn var<K: AsRef<OsStr>>(key: K) -> Result<String, VarError> {
+    let value = current_shell().lock().unwrap().env().get(key.as_ref())
+        .cloned();
+    match value {
+        Some(Some(value)) => value.into_string().map_err(VarError::NotUnicode),
+        Some(None) => Err(VarError::NotPresent),
+        None => env::var(key),
+    }
+}
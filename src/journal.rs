@@ -0,0 +1,202 @@
+// Copyright 2017 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use process_manager::PROCESS_MANAGER;
+use resource_usage::ResourceUsage;
+use result::ShellError;
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// Record of a process launched by a command, written as a JSON line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub argv: Vec<String>,
+    /// Environment variables set on the command, or removed if `None`.
+    pub env: BTreeMap<String, Option<String>>,
+    /// Whether the inherited environment was cleared.
+    pub env_clear: bool,
+    pub cwd: Option<String>,
+    pub pid: u32,
+    /// Thread which spawned the process.
+    pub thread: String,
+    pub start: SystemTime,
+    pub end: Option<SystemTime>,
+    pub status: Option<ExitStatus>,
+    pub usage: Option<ResourceUsage>,
+    /// Why the process has no exit status, e.g. it failed to spawn or was
+    /// dropped without being waited.
+    pub error: Option<String>,
+}
+
+fn write_string(json: &mut String, value: &str) {
+    json.push('"');
+    for ch in value.chars() {
+        match ch {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => {
+                write!(json, "\\u{:04x}", ch as u32).unwrap();
+            }
+            ch => json.push(ch),
+        }
+    }
+    json.push('"');
+}
+
+fn write_optional_string(json: &mut String, value: Option<&String>) {
+    match value {
+        Some(value) => write_string(json, value),
+        None => json.push_str("null"),
+    }
+}
+
+fn write_optional<T: ToString>(json: &mut String, value: Option<T>) {
+    match value {
+        Some(value) => json.push_str(&value.to_string()),
+        None => json.push_str("null"),
+    }
+}
+
+/// Returns seconds since the Unix epoch.
+fn seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0))
+        .as_secs_f64()
+}
+
+impl JournalEntry {
+    /// Formats the entry as a single line of JSON without a newline.
+    ///
+    /// Times are seconds since the Unix epoch and durations are seconds.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"argv\":[");
+        for (i, arg) in self.argv.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write_string(&mut json, arg);
+        }
+        json.push_str("],\"env\":{");
+        for (i, (key, value)) in self.env.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write_string(&mut json, key);
+            json.push(':');
+            write_optional_string(&mut json, value.as_ref());
+        }
+        write!(json, "}},\"env_clear\":{},\"cwd\":", self.env_clear).unwrap();
+        write_optional_string(&mut json, self.cwd.as_ref());
+        write!(json, ",\"pid\":{},\"thread\":", self.pid).unwrap();
+        write_string(&mut json, &self.thread);
+        write!(json, ",\"start\":{},\"end\":", seconds(self.start)).unwrap();
+        write_optional(&mut json, self.end.map(seconds));
+        json.push_str(",\"code\":");
+        write_optional(&mut json, self.status.and_then(|s| s.code()));
+        json.push_str(",\"signal\":");
+        write_optional(&mut json, self.status.and_then(|s| s.signal()));
+        json.push_str(",\"usage\":");
+        match self.usage {
+            Some(ref usage) => write!(
+                json, "{{\"wall_time\":{},\"user_time\":{},\"system_time\":{},\
+                       \"max_rss\":{},\"voluntary_context_switches\":{},\
+                       \"involuntary_context_switches\":{}}}",
+                usage.wall_time.as_secs_f64(), usage.user_time.as_secs_f64(),
+                usage.system_time.as_secs_f64(), usage.max_rss,
+                usage.voluntary_context_switches,
+                usage.involuntary_context_switches).unwrap(),
+            None => json.push_str("null"),
+        }
+        json.push_str(",\"error\":");
+        write_optional_string(&mut json, self.error.as_ref());
+        json.push('}');
+        json
+    }
+}
+
+/// Sink receiving a `JournalEntry` for every process, registered by
+/// `set_journal()`.
+#[derive(Clone)]
+pub struct Journal(Arc<Mutex<dyn Write + Send>>);
+
+impl Journal {
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Journal {
+        Journal(Arc::new(Mutex::new(writer)))
+    }
+
+    /// Creates (or truncates) a file to write the journal.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Journal, ShellError> {
+        Ok(Journal::new(File::create(path)?))
+    }
+
+    pub fn write(&self, entry: &JournalEntry) {
+        let mut writer = self.0.lock().unwrap();
+        let result = writeln!(writer, "{}", entry.to_json())
+            .and_then(|_| writer.flush());
+        if let Err(error) = result {
+            error!("Failed to write journal {:?}", error);
+        }
+    }
+}
+
+/// Registers `journal` on the `ProcessManager`, or unregisters the current
+/// one if `None`. Processes spawned after the call are recorded when they are
+/// waited or dropped, and commands failing to spawn are recorded as well.
+pub fn set_journal(journal: Option<Journal>) {
+    PROCESS_MANAGER.lock().unwrap().set_journal(journal);
+}
+
+/// Returns the journal registered on the `ProcessManager`.
+pub fn journal() -> Option<Journal> {
+    PROCESS_MANAGER.lock().unwrap().journal()
+}
+
+#[test]
+fn test_journal_entry_to_json() {
+    use libc;
+    let mut env = BTreeMap::new();
+    env.insert(String::from("A"), Some(String::from("\"a\"")));
+    env.insert(String::from("B"), None);
+    let entry = JournalEntry {
+        argv: vec![String::from("echo"), String::from("a\nb")],
+        env,
+        env_clear: false,
+        cwd: None,
+        pid: 10,
+        thread: String::from("main"),
+        start: UNIX_EPOCH + Duration::from_millis(1500),
+        end: None,
+        status: Some(ExitStatus::from_raw(libc::SIGTERM)),
+        usage: None,
+        error: None,
+    };
+    assert_eq!(entry.to_json(),
+               "{\"argv\":[\"echo\",\"a\\nb\"],\
+                \"env\":{\"A\":\"\\\"a\\\"\",\"B\":null},\"env_clear\":false,\
+                \"cwd\":null,\"pid\":10,\"thread\":\"main\",\"start\":1.5,\
+                \"end\":null,\"code\":null,\"signal\":15,\"usage\":null,\
+                \"error\":null}");
+}
@@ -109,6 +109,13 @@
 //! thread.  It also stops launching a new process by `ShellComamnd::run()` on
 //! that thread.
 //!
+//! Threads started by `shell::spawn` inside such a thread belong to it, so the
+//! signal also reaches their processes and stops them launching new ones.
+//!
+//! `ShellHandle#join_timeout()` and `ShellHandle#is_finished()` check a thread
+//! without blocking forever, and `ShellHandle#running_processes()` lists the
+//! pid and command line of processes it is running.
+//!
 //! ```
 //! #[macro_use] extern crate shell;
 //! extern crate libc;
@@ -126,40 +133,231 @@
 //! # }
 //! ```
 //!
+//! ## Parallel
+//!
+//! `shell::parallel()` runs commands with bounded concurrency and returns
+//! their results in input order. `Pool` can also stop at the first failure and
+//! run the commands in background, cancellable through a single handle.
+//!
+//! ```
+//! #[macro_use] extern crate shell;
+//! use shell::Pool;
+//!
+//! # fn main() {
+//! let results = shell::parallel(2, vec![cmd!("true"), cmd!("false")]);
+//! assert!(results[0].is_ok());
+//! assert!(results[1].is_err());
+//!
+//! let handle = Pool::new(4).fail_fast(true)
+//!     .spawn((0..8).map(|_| cmd!("sleep 10")));
+//! handle.cancel();
+//! assert!(handle.join().iter().all(|result| result.is_err()));
+//! # }
+//! ```
+//!
+//! `ShellCommand::xargs()` appends many arguments to a command like `xargs`,
+//! running it multiple times so that each command line fits in `ARG_MAX`.
+//! `ShellCommand::xargs_parallel()` runs the batches in parallel.
+//!
+//! ```
+//! #[macro_use] extern crate shell;
+//!
+//! # fn main() {
+//! let files = (0..10000).map(|i| format!("/tmp/no-such-file-{}", i));
+//! cmd!("rm -f").xargs(files).unwrap();
+//! # }
+//! ```
+//!
+//! ## Scope
+//!
+//! `shell::scope()` runs a closure on a nested shell, like
+//! `std::thread::scope()`. Threads started by `Scope::spawn()` can borrow
+//! local variables. When the closure returns or panics, the threads are joined,
+//! and then processes left without wait are terminated by SIGTERM and waited.
+//!
+//! ```
+//! #[macro_use] extern crate shell;
+//!
+//! # fn main() {
+//! let files = vec!["/etc/hostname", "/etc/hosts"];
+//! shell::scope(|s| {
+//!   for file in &files {
+//!     s.spawn(move || cmd!("cat {}", file).run());
+//!   }
+//!   // Terminated at the end of the scope.
+//!   cmd!("sleep 10").spawn().unwrap();
+//! });
+//! # }
+//! ```
+//!
+//! ## Working directory and environment
+//!
+//! `shell::cd()`, `shell::export()` and `shell::unset()` change the working
+//! directory and environment variables of commands run on the current thread
+//! only, unlike `std::env::set_current_dir()` and `std::env::set_var()`. They
+//! also apply to `$VAR` in `cmd!` and are inherited by threads started by
+//! `shell::spawn`. `shell::pushd()` returns a guard which restores the previous
+//! directory when it is dropped.
+//!
+//! ```
+//! #[macro_use] extern crate shell;
+//!
+//! # fn main() {
+//! shell::spawn(|| {
+//!   shell::export("GREETING", "hello");
+//!   let _guard = shell::pushd("/tmp").unwrap();
+//!   assert_eq!(cmd!("pwd").stdout_utf8().unwrap(), "/tmp\n");
+//!   assert_eq!(cmd!("echo $GREETING").stdout_utf8().unwrap(), "hello\n");
+//! }).join().unwrap();
+//! # }
+//! ```
+//!
+//! `ShellHandle#signal()` also cancels the `CancellationToken` of the thread,
+//! so Rust code running between commands can stop promptly by polling
+//! `shell::is_cancelled()` or returning early with `shell::check_cancelled()?`.
+//!
+//! ```
+//! #[macro_use] extern crate shell;
+//! extern crate libc;
+//! use shell::ShellError;
+//!
+//! # fn main() {
+//! let handle = shell::spawn(|| -> Result<(), ShellError> {
+//!   loop {
+//!     shell::check_cancelled()?;
+//!     // Pure Rust work between commands.
+//!   }
+//! });
+//! handle.signal(libc::SIGTERM);
+//! assert!(handle.join().unwrap().is_err());
+//! # }
+//! ```
+//!
 //! ## Signal handling
 //!
-//! `trap_signal_and_wait_children()` starts watching SIGINT and SIGTERM, and
-//! waits all child processes before exiting the process when receiving these
-//! signals. The function needs to be called before launching any new thread.
+//! `trap_signal_and_wait_children()` starts watching SIGINT and SIGTERM. When
+//! receiving these signals, it forwards them to every `LocalShell` and waits
+//! all child processes before exiting the process. Receiving the signal again,
+//! e.g. a second Ctrl-C, kills the remaining processes with SIGKILL. The
+//! function needs to be called before launching any new thread.
 //!
 //! ```
 //! extern crate shell;
 //! shell::trap_signal_and_wait_children().unwrap();
 //! ```
 //!
+//! `SignalTrap` configures the trapped signals, whether to forward them to
+//! child processes, a timeout after which remaining processes receive SIGKILL, and
+//! hooks run before exiting.
+//!
+//! ```no_run
+//! extern crate libc;
+//! extern crate shell;
+//! use shell::SignalTrap;
+//! use std::time::Duration;
+//!
+//! # fn main() {
+//! SignalTrap::new()
+//!     .signal(libc::SIGHUP)
+//!     .shutdown_timeout(Duration::from_secs(10))
+//!     .on_exit(|signal| eprintln!("Exit by signal {}", signal))
+//!     .install()
+//!     .unwrap();
+//! # }
+//! ```
+//!
+//! `become_subreaper()` makes the current process adopt descendants orphaned
+//! by their parents and reaps them in a background thread. Then
+//! `trap_signal_and_wait_children()` also waits for them, and
+//! `kill_descendants()` and `wait_descendants()` are available to stop every
+//! descendant, not only the ones started on a `LocalShell`.
+//!
+//! ## Dry run
+//!
+//! `set_dry_run_default()` enables dry-run mode for every thread and
+//! `set_dry_run()` for the current `LocalShell` only. In dry-run mode, commands
+//! print the quoted command line to stderr and return the given fake result
+//! instead of running. The line includes the working directory, the user and
+//! exported variables, e.g. `(cd /srv && [nobody] X=1 rm -rf build)`.
+//!
+//! ```
+//! #[macro_use] extern crate shell;
+//! use shell::DryRunResult;
+//!
+//! # fn main() {
+//! shell::set_dry_run(Some(DryRunResult::new(0, "v1.0\n")));
+//! assert_eq!(cmd!("git describe").stdout_utf8().unwrap(), "v1.0\n");
+//! cmd!("git push origin v1.0").run().unwrap();
+//! # }
+//! ```
+//!
+//! ## Trace
+//!
+//! `set_xtrace()` prints each command line before it runs and its exit status
+//! and duration afterward, like `set -x`. It applies to the current
+//! `LocalShell` and threads started by `shell::spawn()` from it, and prints to
+//! stderr or to a `log` target.
+//!
+//! ```
+//! #[macro_use] extern crate shell;
+//! use shell::TraceOutput;
+//! use shell::XTrace;
+//!
+//! # fn main() {
+//! shell::set_xtrace(Some(XTrace {
+//!     prefix: String::from("[trace] "),
+//!     output: TraceOutput::Log(String::from("commands")),
+//! }));
+//! cmd!("echo traced").run().unwrap();
+//! shell::set_xtrace(None);
+//! # }
+//! ```
+//!
+//! ## Journal
+//!
+//! `set_journal()` registers a `Journal` on the process manager, which writes
+//! one JSON line for every process with its argv, environment changes, working
+//! directory, pid, thread, start and end time, exit status or signal, and
+//! resource usage. Commands failing to spawn and processes dropped without
+//! being waited are recorded with an error instead of the exit status.
+//! `Journal::create()` writes the journal to a file.
+//!
+//! ```
+//! #[macro_use] extern crate shell;
+//! use shell::Journal;
+//! use shell::TeeBuffer;
+//!
+//! # fn main() {
+//! let buffer = TeeBuffer::new();
+//! shell::set_journal(Some(Journal::new(buffer.clone())));
+//! cmd!("echo journaled").run().unwrap();
+//! shell::set_journal(None);
+//! assert!(buffer.to_string_lossy().starts_with("{\"argv\":[\"echo\""));
+//! # }
+//! ```
+//!
 //! ## Access underlaying objects
 //!
-//! `ShellComamnd` wraps `std::process::Command` and `ShellChild` wraps
-//! `std::process::Child`. Both underlaying objects are accessible via public
-//! fields.
+//! `ShellComamnd` describes a command and can be cloned to run it multiple
+//! times. `command()` builds `std::process::Command` from the description.
+//! `ShellChild` wraps `std::process::Child`, which is accessible via
+//! `ShellChild::process()`.
 //!
 //! ```
 //! #[macro_use] extern crate shell;
-//! use std::process::Stdio;
+//! use shell::StdioSpec;
 //! use std::io::Read;
 //!
 //! # fn main() {
-//! // Access std::process::Command.
-//! let mut shell_command = cmd!("echo OK");
-//! {
-//!   let mut command = &mut shell_command.command;
-//!   command.stdout(Stdio::piped());
-//! }
+//! // Build std::process::Command.
+//! let shell_command = cmd!("echo OK").stdout(StdioSpec::Piped);
+//! let output = shell_command.command().output().unwrap();
+//! assert_eq!(output.stdout, b"OK\n");
 //!
 //! // Access std::process::Child.
 //! let shell_child = shell_command.spawn().unwrap();
 //! {
-//!   let mut lock = shell_child.0.write().unwrap();
+//!   let mut lock = shell_child.process().write().unwrap();
 //!   let mut child = &mut lock.as_mut().unwrap().child;
 //!   let mut str = String::new();
 //!   child.stdout.as_mut().unwrap().read_to_string(&mut str);
@@ -180,21 +378,87 @@ extern crate regex;
 extern crate env_logger;
 
 #[macro_use] mod command;
+mod cancellation;
+mod capture;
+mod dry_run;
+mod environment;
 mod shell_child;
+mod signal_trap;
 mod shell_command;
+mod pool;
 mod process_manager;
+mod pty;
+mod journal;
 mod local_shell;
+mod resource_usage;
 mod result;
+mod retry;
+mod scope;
+mod stream;
+mod subreaper;
+mod tee;
+mod user;
+mod xargs;
+mod xtrace;
 
+pub use cancellation::CancellationToken;
+pub use cancellation::cancellation_token;
+pub use cancellation::check_cancelled;
+pub use cancellation::is_cancelled;
+pub use capture::Chunk;
+pub use capture::Stream;
+pub use capture::Transcript;
 pub use command::new_command;
+pub use dry_run::DryRunResult;
+pub use dry_run::set_dry_run;
+pub use dry_run::set_dry_run_default;
+pub use environment::PushdGuard;
+pub use environment::cd;
+pub use environment::cwd;
+pub use environment::export;
+pub use environment::pushd;
+pub use environment::unset;
+pub use environment::var;
+pub use journal::Journal;
+pub use journal::JournalEntry;
+pub use journal::set_journal;
+pub use local_shell::ProcessInfo;
 pub use local_shell::ShellHandle;
 pub use local_shell::spawn;
+pub use pool::Pool;
+pub use pool::PoolHandle;
+pub use pool::parallel;
 pub use process_manager::trap_signal_and_wait_children;
+pub use pty::Pty;
+pub use resource_usage::ResourceUsage;
 pub use result::ShellError;
 pub use result::ShellResult;
 pub use result::ShellResultExt;
+pub use result::SuccessPredicate;
+pub use result::SuccessfulExit;
 pub use result::ok;
+pub use retry::RetryPolicy;
+pub use retry::RetryPredicate;
+pub use scope::Scope;
+pub use scope::ScopedShellHandle;
+pub use scope::scope;
 pub use shell_child::ShellChild;
 pub use shell_child::ShellChildArc;
 pub use shell_child::ShellChildCore;
+pub use shell_command::IoPriority;
+pub use shell_command::RlimitResource;
 pub use shell_command::ShellCommand;
+pub use shell_command::StdioSpec;
+pub use shell_command::set_die_with_parent_default;
+pub use signal_trap::CleanupHook;
+pub use signal_trap::SignalTrap;
+pub use stream::ShellLines;
+pub use subreaper::become_subreaper;
+pub use subreaper::kill_descendants;
+pub use subreaper::wait_descendants;
+pub use tee::TeeBuffer;
+pub use tee::TeeTarget;
+pub use xtrace::TraceOutput;
+pub use xtrace::XTrace;
+pub use xtrace::set_xtrace;
+pub use xtrace::xtrace;
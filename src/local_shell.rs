@@ -12,29 +12,63 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use cancellation::CancellationToken;
+use dry_run::DryRunResult;
 use shell_child::ShellChildArc;
+use shell_child::ShellChildCore;
 use libc::c_int;
 use process_manager::PROCESS_MANAGER;
 use std::any::Any;
 use std::cell::RefCell;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::ops::Deref;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::Weak;
 use std::thread::JoinHandle;
 use std::thread::ThreadId;
+use std::time::Duration;
+use std::time::Instant;
 use std::thread;
+use xtrace::XTrace;
+
+/// Snapshot of a process running on a `LocalShell`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub command_line: String,
+}
 
 /// Thread local shell.
+///
+/// Shells of threads started by `shell::spawn()` are children of the shell of
+/// the spawning thread, so signals reach the whole subtree.
 pub struct LocalShell {
     processes: Vec<ShellChildArc>,
-    signaled: bool
+    children: Vec<Weak<Mutex<LocalShell>>>,
+    /// Cancelled when the shell is signaled.
+    cancellation: CancellationToken,
+    dry_run: Option<DryRunResult>,
+    xtrace: Option<XTrace>,
+    /// Working directory of commands, or the process's one if `None`.
+    cwd: Option<PathBuf>,
+    /// Environment variables set on commands, or removed if `None`.
+    env: BTreeMap<OsString, Option<OsString>>,
 }
 
 impl LocalShell {
     fn new() -> LocalShell {
         LocalShell {
             processes: Vec::new(),
-            signaled: false
+            children: Vec::new(),
+            cancellation: CancellationToken::new(),
+            dry_run: None,
+            xtrace: None,
+            cwd: None,
+            env: BTreeMap::new(),
         }
     }
 
@@ -46,35 +80,120 @@ impl LocalShell {
         self.processes.retain(|p| !Arc::ptr_eq(p, process));
     }
 
+    /// Registers the shell of a thread started from this shell's thread.
+    fn add_child(&mut self, shell: &Arc<Mutex<LocalShell>>) {
+        self.children.retain(|child| child.upgrade().is_some());
+        self.children.push(Arc::downgrade(shell));
+    }
+
+    /// Returns the live shells of threads started from this shell's thread.
+    pub fn children(&self) -> Vec<Arc<Mutex<LocalShell>>> {
+        self.children.iter().filter_map(|child| child.upgrade()).collect()
+    }
+
+    /// Sends `signal` to the processes of this shell and its descendant
+    /// shells, and marks all of them as signaled.
     pub fn signal(&mut self, signal: c_int) {
-        self.signaled = true;
+        self.signal_if(signal, &|_| true);
+    }
+
+    /// Same as `signal()`, but skips processes for which `predicate` returns
+    /// false.
+    pub fn signal_if(&mut self, signal: c_int,
+                     predicate: &dyn Fn(&ShellChildCore) -> bool) {
+        self.cancellation.cancel();
         for process in &self.processes {
             let lock = process.read().unwrap();
             if let Some(child) = lock.as_ref() {
+                if !predicate(child) {
+                    continue;
+                }
                 if let Err(error) = child.signal(signal) {
                     error!("Failed to send a signal {:?}", error);
                 }
             }
         }
+        // Shells are always locked from parent to child.
+        for child in &self.children {
+            if let Some(child) = child.upgrade() {
+                child.lock().unwrap().signal_if(signal, predicate);
+            }
+        }
+    }
+
+    /// Unregisters the processes which have not been waited yet.
+    fn take_processes(&mut self) -> Vec<ShellChildCore> {
+        self.processes.drain(..)
+            .filter_map(|process| process.write().unwrap().take())
+            .collect()
     }
 
-    pub fn wait(&mut self) {
+    /// Returns processes of this shell and its descendant shells which have
+    /// not been waited yet.
+    pub fn running_processes(&self) -> Vec<ProcessInfo> {
+        let mut result = Vec::new();
         for process in &self.processes {
-            let mut lock = process.write().unwrap();
-            if let Some(child) = lock.take() {
-                if let Err(error) = child.wait() {
-                    error!("Failed to wait process {:?}", error);
-                }
+            let lock = process.read().unwrap();
+            if let Some(child) = lock.as_ref() {
+                result.push(ProcessInfo {
+                    pid: child.child.id(),
+                    command_line: child.command_line().to_string(),
+                });
             }
         }
+        for child in &self.children {
+            if let Some(child) = child.upgrade() {
+                result.extend(child.lock().unwrap().running_processes());
+            }
+        }
+        result
     }
 
     pub fn signaled(&self) -> bool {
-        self.signaled
+        self.cancellation.is_cancelled()
+    }
+
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    pub fn set_dry_run(&mut self, result: Option<DryRunResult>) {
+        self.dry_run = result;
+    }
+
+    pub fn dry_run(&self) -> Option<DryRunResult> {
+        self.dry_run.clone()
+    }
+
+    pub fn set_xtrace(&mut self, xtrace: Option<XTrace>) {
+        self.xtrace = xtrace;
+    }
+
+    pub fn xtrace(&self) -> Option<XTrace> {
+        self.xtrace.clone()
+    }
+
+    pub fn set_cwd(&mut self, cwd: Option<PathBuf>) {
+        self.cwd = cwd;
+    }
+
+    pub fn cwd(&self) -> Option<PathBuf> {
+        self.cwd.clone()
+    }
+
+    /// Sets an environment variable, or removes it if `value` is `None`.
+    pub fn set_var(&mut self, key: OsString, value: Option<OsString>) {
+        self.env.insert(key, value);
+    }
+
+    pub fn env(&self) -> &BTreeMap<OsString, Option<OsString>> {
+        &self.env
     }
 }
 
-struct LocalShellScope(ThreadId, Arc<Mutex<LocalShell>>);
+/// Current shell of a thread, registered for the thread unless it is nested in
+/// the thread's previous shell.
+struct LocalShellScope(Option<ThreadId>, Arc<Mutex<LocalShell>>);
 
 impl LocalShellScope {
     fn new(arc: &Arc<Mutex<LocalShell>>) -> LocalShellScope {
@@ -82,7 +201,7 @@ impl LocalShellScope {
         let id = thread::current().id();
         lock.add_local_shell(&id, arc);
 
-        LocalShellScope(id, arc.clone())
+        LocalShellScope(Some(id), arc.clone())
     }
 }
 
@@ -94,8 +213,10 @@ impl Default for LocalShellScope {
 
 impl Drop for LocalShellScope {
     fn drop(&mut self) {
-        let mut lock = PROCESS_MANAGER.lock().unwrap();
-        lock.remove_local_shell(&self.0);
+        if let Some(ref id) = self.0 {
+            let mut lock = PROCESS_MANAGER.lock().unwrap();
+            lock.remove_local_shell(id);
+        }
     }
 }
 
@@ -110,9 +231,40 @@ impl <T> ShellHandle<T> {
         lock.signal(signal);
     }
 
+    /// Returns the token cancelled when the thread's shell is signaled.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.shell.lock().unwrap().cancellation_token()
+    }
+
     pub fn join(self) -> Result<T, Box<Any + Send + 'static>> {
         self.join_handle.join()
     }
+
+    /// Joins the thread if it finishes within `timeout`. Otherwise returns the
+    /// handle back so that it can be signaled or joined later.
+    pub fn join_timeout(self, timeout: Duration) -> Result<
+            Result<T, Box<dyn Any + Send + 'static>>, ShellHandle<T>> {
+        let deadline = Instant::now() + timeout;
+        while !self.is_finished() {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(self);
+            }
+            thread::sleep(cmp::min(deadline - now, Duration::from_millis(10)));
+        }
+        Ok(self.join())
+    }
+
+    /// Returns whether the thread has finished, without blocking.
+    pub fn is_finished(&self) -> bool {
+        self.join_handle.is_finished()
+    }
+
+    /// Returns processes running on the thread's shell, including ones of
+    /// threads spawned from it, e.g. to see what a stuck job is doing.
+    pub fn running_processes(&self) -> Vec<ProcessInfo> {
+        self.shell.lock().unwrap().running_processes()
+    }
 }
 
 impl <T> Deref for ShellHandle<T> {
@@ -122,9 +274,64 @@ impl <T> Deref for ShellHandle<T> {
     }
 }
 
+/// Waits the processes of `shell` which have not been waited yet.
+///
+/// The locks are released before waiting, as reader threads can outlive the
+/// processes while their descendants keep the output open.
+pub fn wait_processes(shell: &Arc<Mutex<LocalShell>>) {
+    let processes = shell.lock().unwrap().take_processes();
+    for process in processes {
+        if let Err(error) = process.wait() {
+            error!("Failed to wait process {:?}", error);
+        }
+    }
+}
+
+/// Creates a shell registered as a child of `parent`, inheriting its state.
+pub fn new_child_shell(parent: &Arc<Mutex<LocalShell>>)
+        -> Arc<Mutex<LocalShell>> {
+    let mut parent = parent.lock().unwrap();
+    let mut shell = LocalShell::new();
+    if parent.signaled() {
+        shell.cancellation.cancel();
+    }
+    shell.dry_run = parent.dry_run();
+    shell.xtrace = parent.xtrace();
+    shell.cwd = parent.cwd();
+    shell.env = parent.env.clone();
+    let arc = Arc::new(Mutex::new(shell));
+    parent.add_child(&arc);
+    arc
+}
+
+/// Makes a shell the current shell of the thread until it is dropped.
+pub struct EnterShellGuard(Option<LocalShellScope>);
+
+impl Drop for EnterShellGuard {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        LOCAL_SHELL_SCOPE.with(|shell| *shell.borrow_mut() = previous);
+    }
+}
+
+/// Makes `shell` the current shell of the thread until the returned guard is
+/// dropped.
+///
+/// `shell` must be a child of the thread's previous shell if any. The previous
+/// shell stays registered, so that signals reach both through it.
+pub fn enter_shell(shell: &Arc<Mutex<LocalShell>>) -> EnterShellGuard {
+    let previous = LOCAL_SHELL_SCOPE.with(|scope| scope.borrow_mut().take());
+    let scope = match previous {
+        Some(_) => LocalShellScope(None, shell.clone()),
+        None => LocalShellScope::new(shell),
+    };
+    LOCAL_SHELL_SCOPE.with(|shell| *shell.borrow_mut() = Some(scope));
+    EnterShellGuard(previous)
+}
+
 pub fn spawn<F, T>(f: F) -> ShellHandle<T> where
         F: FnOnce() -> T, F: Send + 'static, T: Send + 'static {
-    let arc = Arc::new(Mutex::new(LocalShell::new()));
+    let arc = new_child_shell(&current_shell());
     let arc_clone = arc.clone();
     let join_handle = thread::spawn(move || -> T {
         LOCAL_SHELL_SCOPE.with(|shell| {
@@ -145,7 +352,7 @@ pub fn spawn<F, T>(f: F) -> ShellHandle<T> where
 pub fn current_shell() -> Arc<Mutex<LocalShell>> {
     LOCAL_SHELL_SCOPE.with(|shell| {
         shell.borrow_mut()
-            .get_or_insert(LocalShellScope::default()).1.clone()
+            .get_or_insert_with(LocalShellScope::default).1.clone()
     })
 }
 
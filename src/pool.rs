@@ -0,0 +1,146 @@
+// Copyright 2017 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cancellation::CancellationToken;
+use libc::c_int;
+use libc;
+use local_shell::ShellHandle;
+use local_shell::current_shell;
+use local_shell::spawn;
+use result::ShellError;
+use result::ShellResult;
+use shell_command::ShellCommand;
+use std::cmp;
+use std::collections::VecDeque;
+use std::panic;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// Runs commands in parallel with bounded concurrency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pool {
+    max_jobs: usize,
+    fail_fast: bool,
+}
+
+impl Pool {
+    /// Creates a pool running up to `max_jobs` commands at once. It keeps
+    /// going when a command fails by default.
+    pub fn new(max_jobs: usize) -> Pool {
+        Pool {
+            max_jobs: cmp::max(max_jobs, 1),
+            fail_fast: false,
+        }
+    }
+
+    /// Makes the first failure send SIGTERM to the running commands and skip
+    /// the remaining ones.
+    pub fn fail_fast(mut self, fail_fast: bool) -> Pool {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    /// Starts running `commands` on a thread started by `shell::spawn()`.
+    pub fn spawn<I>(self, commands: I) -> PoolHandle
+            where I: IntoIterator<Item = ShellCommand> {
+        let queue = commands.into_iter().enumerate().collect::<VecDeque<_>>();
+        let pool = self;
+        PoolHandle(spawn(move || pool.run_queue(queue)))
+    }
+
+    /// Runs `commands` and returns their results in input order.
+    pub fn run<I>(self, commands: I) -> Vec<ShellResult>
+            where I: IntoIterator<Item = ShellCommand> {
+        self.spawn(commands).join()
+    }
+
+    fn run_queue(self, queue: VecDeque<(usize, ShellCommand)>)
+            -> Vec<ShellResult> {
+        let count = queue.len();
+        let queue = Arc::new(Mutex::new(queue));
+        let results = Arc::new(Mutex::new(
+                (0..count).map(|_| None).collect::<Vec<Option<ShellResult>>>()));
+        let pool_shell = current_shell();
+        let workers = (0..cmp::min(self.max_jobs, count)).map(|_| {
+            let queue = queue.clone();
+            let results = results.clone();
+            let pool_shell = pool_shell.clone();
+            let fail_fast = self.fail_fast;
+            spawn(move || loop {
+                let (index, command) = match queue.lock().unwrap().pop_front() {
+                    Some(entry) => entry,
+                    None => break,
+                };
+                let result = if pool_shell.lock().unwrap().signaled() {
+                    Err(ShellError::Cancelled)
+                } else {
+                    command.run()
+                };
+                if fail_fast && result.is_err() {
+                    // Reaches every worker, as they are children of the pool.
+                    pool_shell.lock().unwrap().signal(libc::SIGTERM);
+                }
+                results.lock().unwrap()[index] = Some(result);
+            })
+        }).collect::<Vec<_>>();
+        for worker in workers {
+            if let Err(error) = worker.join() {
+                panic::resume_unwind(error);
+            }
+        }
+        let mut results = results.lock().unwrap();
+        results.drain(..)
+            .map(|result| result.unwrap_or(Err(ShellError::Cancelled)))
+            .collect()
+    }
+}
+
+/// Handle of commands started by `Pool::spawn()`.
+pub struct PoolHandle(ShellHandle<Vec<ShellResult>>);
+
+impl PoolHandle {
+    /// Sends a signal to every running command and skips the remaining ones.
+    pub fn signal(&self, signal: c_int) {
+        self.0.signal(signal);
+    }
+
+    /// Same as `signal(SIGTERM)`.
+    pub fn cancel(&self) {
+        self.signal(libc::SIGTERM);
+    }
+
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.0.cancellation_token()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.0.is_finished()
+    }
+
+    /// Waits for the commands and returns their results in input order.
+    /// Commands skipped by cancellation result in `ShellError::Cancelled`.
+    pub fn join(self) -> Vec<ShellResult> {
+        match self.0.join() {
+            Ok(results) => results,
+            Err(error) => panic::resume_unwind(error),
+        }
+    }
+}
+
+/// Runs `commands` with up to `max_jobs` at once and returns their results in
+/// input order. Same as `Pool::new(max_jobs).run(commands)`.
+pub fn parallel<I>(max_jobs: usize, commands: I) -> Vec<ShellResult>
+        where I: IntoIterator<Item = ShellCommand> {
+    Pool::new(max_jobs).run(commands)
+}
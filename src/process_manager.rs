@@ -12,32 +12,37 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use libc::c_int;
-use libc;
-use libc::sigset_t;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread::ThreadId;
+use journal::Journal;
 use local_shell::LocalShell;
 use std::collections::HashMap;
-use result::check_errno;
 use result::ShellError;
-use std::mem;
-use errno::Errno;
-use std::thread;
+use signal_trap::SignalTrap;
 
 /// Managing global child process state.
 pub struct ProcessManager {
-    children: HashMap<ThreadId, Arc<Mutex<LocalShell>>>
+    children: HashMap<ThreadId, Arc<Mutex<LocalShell>>>,
+    journal: Option<Journal>,
 }
 
 impl ProcessManager {
     fn new() -> ProcessManager {
         ProcessManager {
-            children: HashMap::new()
+            children: HashMap::new(),
+            journal: None,
         }
     }
 
+    pub fn set_journal(&mut self, journal: Option<Journal>) {
+        self.journal = journal;
+    }
+
+    pub fn journal(&self) -> Option<Journal> {
+        self.journal.clone()
+    }
+
     pub fn add_local_shell(&mut self, id: &ThreadId,
                            shell: &Arc<Mutex<LocalShell>>) {
         self.children.insert(id.clone(), shell.clone());
@@ -46,53 +51,25 @@ impl ProcessManager {
     pub fn remove_local_shell(&mut self, id: &ThreadId) {
         self.children.remove(id);
     }
+
+    /// Unregisters and returns all shells.
+    pub fn drain_local_shells(&mut self) -> Vec<Arc<Mutex<LocalShell>>> {
+        self.children.drain().map(|(_, shell)| shell).collect()
+    }
 }
 
-/// Traps SIGINT and SIGTERM, waits for child process completion, and exits
-/// the current process.
+/// Traps SIGINT and SIGTERM, forwards the signal to every `LocalShell`, waits
+/// for child process completion, and exits the current process. A second
+/// signal sends SIGKILL to the remaining processes.
+///
+/// If `become_subreaper()` has been called, it also waits for orphaned
+/// descendants. Use `SignalTrap` to configure the behavior.
 ///
 /// It must be invoked before any thread is launched, because it internally
 /// uses pthread_sigmask.
 #[allow(dead_code)]
 pub fn trap_signal_and_wait_children() -> Result<(), ShellError> {
-    unsafe {
-        let mut sigset = mem::uninitialized::<sigset_t>();
-        check_errno("sigemptyset",
-                    libc::sigemptyset(&mut sigset as *mut sigset_t))?;
-        check_errno("sigaddset", libc::sigaddset(
-                &mut sigset as *mut sigset_t, libc::SIGINT))?;
-        check_errno("sigaddset", libc::sigaddset(
-                &mut sigset as *mut sigset_t, libc::SIGTERM))?;
-
-        let mut oldset = mem::uninitialized::<sigset_t>();
-        let result = libc::pthread_sigmask(
-            libc::SIG_BLOCK, &mut sigset as *mut sigset_t,
-            &mut oldset as *mut sigset_t);
-        if result != 0 {
-            return Err(ShellError::Errno("pthread_sigmask", Errno(result)));
-        }
-
-        thread::spawn(move || {
-            info!("Start waitinig signal");
-            let mut signal: c_int = 0;
-            let result = libc::sigwait(
-                &sigset as *const sigset_t, &mut signal as *mut c_int);
-            if result != 0 {
-                eprintln!("sigwait failed {}", result);
-                return;
-            }
-            info!("Signal {} is received", signal);
-            let mut lock = PROCESS_MANAGER.lock().unwrap();
-            let mut children = lock.children.drain().collect::<Vec<_>>();
-            info!("Wait for {} child processes exiting", children.len());
-            for &mut (_, ref entry) in &mut children {
-                let mut lock = entry.lock().unwrap();
-                lock.wait();
-            }
-            ::std::process::exit(128 + signal);
-        });
-        Ok(())
-    }
+    SignalTrap::new().install()
 }
 
 lazy_static! {
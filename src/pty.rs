@@ -0,0 +1,97 @@
+// Copyright 2017 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use libc::c_int;
+use libc;
+use result::ShellError;
+use result::check_errno;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
+use std::ptr;
+
+fn window_size(rows: u16, cols: u16) -> libc::winsize {
+    libc::winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+/// Opens a pseudo-terminal and returns its master and slave sides.
+pub fn open_pty(rows: u16, cols: u16) -> Result<(Pty, File), ShellError> {
+    let mut master: c_int = -1;
+    let mut slave: c_int = -1;
+    let size = window_size(rows, cols);
+    unsafe {
+        check_errno("openpty", libc::openpty(
+                &mut master, &mut slave, ptr::null_mut(), ptr::null(),
+                &size))?;
+        let master = File::from_raw_fd(master);
+        let slave = File::from_raw_fd(slave);
+        // Other child processes must not inherit the pty.
+        for fd in &[master.as_raw_fd(), slave.as_raw_fd()] {
+            check_errno("fcntl", libc::fcntl(
+                    *fd, libc::F_SETFD, libc::FD_CLOEXEC))?;
+        }
+        Ok((Pty(master), slave))
+    }
+}
+
+/// Master side of a pseudo-terminal allocated by `ShellCommand::pty()`.
+///
+/// Reading returns the output of the command, with line endings converted to
+/// "\r\n" by the terminal. Writing sends input to the command.
+#[derive(Debug)]
+pub struct Pty(File);
+
+impl Pty {
+    pub fn try_clone(&self) -> Result<Pty, ShellError> {
+        Ok(Pty(self.0.try_clone()?))
+    }
+
+    /// Changes the window size. The kernel sends SIGWINCH to the command.
+    pub fn resize(&self, rows: u16, cols: u16) -> Result<(), ShellError> {
+        let size = window_size(rows, cols);
+        unsafe {
+            check_errno("ioctl", libc::ioctl(
+                    self.0.as_raw_fd(), libc::TIOCSWINSZ, &size))?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for Pty {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            // Linux returns EIO once all the slave sides are closed.
+            Err(ref error) if error.raw_os_error() == Some(libc::EIO) => Ok(0),
+            result => result,
+        }
+    }
+}
+
+impl Write for Pty {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
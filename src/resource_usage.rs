@@ -0,0 +1,46 @@
+// Copyright 2017 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use libc;
+use std::time::Duration;
+
+/// Resources consumed by a finished command, obtained by `wait4`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceUsage {
+    /// Time from spawning the process until it is waited.
+    pub wall_time: Duration,
+    pub user_time: Duration,
+    pub system_time: Duration,
+    /// Maximum resident set size in kilobytes.
+    pub max_rss: u64,
+    pub voluntary_context_switches: u64,
+    pub involuntary_context_switches: u64,
+}
+
+fn from_timeval(time: &libc::timeval) -> Duration {
+    Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000)
+}
+
+impl ResourceUsage {
+    pub fn new(wall_time: Duration, usage: &libc::rusage) -> ResourceUsage {
+        ResourceUsage {
+            wall_time,
+            user_time: from_timeval(&usage.ru_utime),
+            system_time: from_timeval(&usage.ru_stime),
+            max_rss: usage.ru_maxrss as u64,
+            voluntary_context_switches: usage.ru_nvcsw as u64,
+            involuntary_context_switches: usage.ru_nivcsw as u64,
+        }
+    }
+}
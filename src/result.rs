@@ -16,14 +16,17 @@ extern crate errno;
 extern crate libc;
 
 use errno::Errno;
+use capture::Transcript;
 use errno::errno;
+use resource_usage::ResourceUsage;
 use std::convert::From;
-use std::default::Default;
 use std::env;
+use std::error;
+use std::fmt;
 use std::io;
-use std::marker::PhantomData;
 use std::os::unix::process::ExitStatusExt;
 use std::process::ExitStatus;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub enum ShellError {
@@ -32,14 +35,102 @@ pub enum ShellError {
     VarError(env::VarError),
     Errno(&'static str, Errno),
     NoSuchProcess,
+    NoSuchUser(String),
+    /// The command could not switch to the requested user or group.
+    Privilege(String, io::Error),
+    /// Error of a command run with `ShellCommand::capture_output()`, with the
+    /// output recorded until the failure.
+    WithTranscript(Box<ShellError>, Transcript),
+    /// Error of a command run with `ShellCommand::collect_usage()`, with the
+    /// resources it consumed.
+    WithUsage(Box<ShellError>, ResourceUsage),
+    /// The `LocalShell` was signaled. Returned by `check_cancelled()`.
+    Cancelled,
 }
 
 impl ShellError {
     pub fn from_signal(command: String, signal: u8) -> Self {
         ShellError::Status(command, ExitStatus::from_raw(128 + signal as i32))
     }
+
+    /// Returns the output recorded by `ShellCommand::capture_output()`.
+    pub fn transcript(&self) -> Option<&Transcript> {
+        match *self {
+            ShellError::WithTranscript(_, ref transcript) => Some(transcript),
+            ShellError::WithUsage(ref error, _) => error.transcript(),
+            _ => None
+        }
+    }
+
+    /// Returns resources consumed by the failed command if it was run with
+    /// `ShellCommand::collect_usage()`.
+    pub fn usage(&self) -> Option<&ResourceUsage> {
+        match *self {
+            ShellError::WithUsage(_, ref usage) => Some(usage),
+            ShellError::WithTranscript(ref error, _) => error.usage(),
+            _ => None
+        }
+    }
+
+    /// Returns the exit status if the command exited unsuccessfully.
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        match *self {
+            ShellError::Status(_, status) => Some(status),
+            ShellError::WithTranscript(ref error, _) |
+            ShellError::WithUsage(ref error, _) => error.exit_status(),
+            _ => None
+        }
+    }
 }
 
+/// Describes signals sent by the kernel when a resource limit is exceeded.
+fn limit_signal_description(signal: i32) -> Option<&'static str> {
+    match signal {
+        libc::SIGXCPU => Some("CPU time limit exceeded"),
+        libc::SIGXFSZ => Some("File size limit exceeded"),
+        _ => None
+    }
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ShellError::Status(ref command, ref status) => {
+                match (status.code(), status.signal()) {
+                    (Some(code), _) =>
+                        write!(f, "`{}` exited with code {}", command, code),
+                    (None, Some(signal)) => {
+                        write!(f, "`{}` was killed by signal {}",
+                               command, signal)?;
+                        match limit_signal_description(signal) {
+                            Some(description) =>
+                                write!(f, " ({})", description),
+                            None => Ok(())
+                        }
+                    }
+                    (None, None) =>
+                        write!(f, "`{}` exited with {:?}", command, status),
+                }
+            }
+            ShellError::IoError(ref error) => write!(f, "{}", error),
+            ShellError::VarError(ref error) => write!(f, "{}", error),
+            ShellError::Errno(name, ref errno) =>
+                write!(f, "{} failed: {}", name, errno),
+            ShellError::NoSuchProcess => write!(f, "No such process"),
+            ShellError::NoSuchUser(ref name) =>
+                write!(f, "No such user: {}", name),
+            ShellError::Privilege(ref command, ref error) =>
+                write!(f, "Insufficient privilege to run `{}`: {}",
+                       command, error),
+            ShellError::WithTranscript(ref error, _) |
+            ShellError::WithUsage(ref error, _) => write!(f, "{}", error),
+            ShellError::Cancelled => write!(f, "Cancelled"),
+        }
+    }
+}
+
+impl error::Error for ShellError {}
+
 impl From<io::Error> for ShellError {
     fn from(error: io::Error) -> ShellError {
         ShellError::IoError(error)
@@ -52,11 +143,36 @@ impl From<env::VarError> for ShellError {
     }
 }
 
-pub struct SuccessfulExit(PhantomData<SuccessfulExit>);
+/// Function deciding whether a command succeeded from its exit status.
+pub type SuccessPredicate = Arc<dyn Fn(&ExitStatus) -> bool + Send + Sync>;
 
-impl Default for SuccessfulExit {
-    fn default() -> Self {
-        SuccessfulExit(PhantomData::default())
+#[derive(Default)]
+pub struct SuccessfulExit {
+    status: Option<ExitStatus>,
+    usage: Option<ResourceUsage>,
+    transcript: Option<Transcript>,
+}
+
+impl SuccessfulExit {
+    pub fn with_usage(mut self, usage: ResourceUsage) -> Self {
+        self.usage = Some(usage);
+        self
+    }
+
+    pub fn with_transcript(mut self, transcript: Transcript) -> Self {
+        self.transcript = Some(transcript);
+        self
+    }
+
+    /// Returns the output recorded by `ShellCommand::capture_output()`.
+    pub fn transcript(&self) -> Option<&Transcript> {
+        self.transcript.as_ref()
+    }
+
+    /// Returns resources consumed by the command if it was run with
+    /// `ShellCommand::collect_usage()`.
+    pub fn usage(&self) -> Option<&ResourceUsage> {
+        self.usage.as_ref()
     }
 }
 
@@ -73,7 +189,21 @@ pub fn check_errno(name: &'static str,
 
 /// Returns `ShellResult` which is `Ok`.
 pub fn ok() -> ShellResult {
-    Ok(SuccessfulExit(PhantomData::default()))
+    Ok(SuccessfulExit::default())
+}
+
+/// Same as `ShellResultExt::from_status()`, but `success` decides whether the
+/// status is successful instead of `ExitStatus::success()`.
+pub fn from_status_if(command: String, status: ExitStatus,
+                      success: &dyn Fn(&ExitStatus) -> bool) -> ShellResult {
+    if success(&status) {
+        Ok(SuccessfulExit {
+            status: Some(status),
+            ..SuccessfulExit::default()
+        })
+    } else {
+        Err(ShellError::Status(command, status))
+    }
 }
 
 pub trait ShellResultExt {
@@ -85,32 +215,37 @@ pub trait ShellResultExt {
 impl ShellResultExt for ShellResult {
     fn from_status(command: String, status: ExitStatus)
             -> Self {
-        if status.success() {
-            Ok(SuccessfulExit(PhantomData::default()))
-        } else {
-            Err(ShellError::Status(command, status))
-        }
+        from_status_if(command, status, &|status| status.success())
     }
 
     fn status(self) -> Result<ExitStatus, ShellError> {
         match self {
-            Ok(_) => Ok(ExitStatus::from_raw(0)),
+            Ok(exit) => Ok(exit.status.unwrap_or(ExitStatus::from_raw(0))),
             Err(ShellError::Status(_, status)) => Ok(status),
+            Err(ShellError::WithTranscript(error, _)) |
+            Err(ShellError::WithUsage(error, _)) => Err(*error).status(),
             Err(error) => Err(error)
         }
     }
 
     fn code(&self) -> u8 {
-        match self {
-            &Ok(_) => 0,
-            &Err(ShellError::Status(_, ref status)) => {
-                status.code().unwrap_or(1) as u8
-            },
-            &Err(_) => 1
+        match *self {
+            Ok(ref exit) => exit.status.and_then(|status| status.code())
+                .unwrap_or(0) as u8,
+            Err(ref error) => error.exit_status()
+                .and_then(|status| status.code()).unwrap_or(1) as u8
         }
     }
 }
 
+#[test]
+fn test_display_limit_signal() {
+    let error = ShellError::Status(String::from("cmd"),
+                                   ExitStatus::from_raw(libc::SIGXCPU));
+    assert_eq!(error.to_string(),
+               "`cmd` was killed by signal 24 (CPU time limit exceeded)");
+}
+
 #[test]
 fn test_from_raw() {
     let s = ExitStatus::from_raw(128 + 15);
@@ -0,0 +1,93 @@
+// Copyright 2017 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cancellation::cancellation_token;
+use result::ShellError;
+use result::ShellResult;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Function deciding whether a failed attempt is retried.
+pub type RetryPredicate = Arc<dyn Fn(&ShellError) -> bool + Send + Sync>;
+
+/// How `ShellCommand::run()` retries a failing command.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts including the first one.
+    pub attempts: u32,
+    /// Delay before the second attempt, doubled for each following attempt.
+    pub backoff: Duration,
+    pub retry_on: RetryPredicate,
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("attempts", &self.attempts)
+            .field("backoff", &self.backoff)
+            .finish_non_exhaustive()
+    }
+}
+
+/// `retry_on` is equal only if it is shared.
+impl PartialEq for RetryPolicy {
+    fn eq(&self, other: &RetryPolicy) -> bool {
+        self.attempts == other.attempts && self.backoff == other.backoff &&
+            Arc::ptr_eq(&self.retry_on, &other.retry_on)
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Three attempts starting with one second of backoff, retrying when the
+    /// command exits with an error status.
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_secs(1),
+            retry_on: Arc::new(|error| error.exit_status().is_some()),
+        }
+    }
+}
+
+/// Sleeps for `duration`, returning false as soon as the current shell is
+/// signaled.
+fn sleep_unless_signaled(duration: Duration) -> bool {
+    !cancellation_token().wait_timeout(duration)
+}
+
+/// Calls `attempt` until it succeeds or `policy` gives up.
+pub fn retry<F>(policy: &RetryPolicy, line: &str, mut attempt: F)
+        -> ShellResult where F: FnMut() -> ShellResult {
+    let mut backoff = policy.backoff;
+    let mut count = 1;
+    loop {
+        let error = match attempt() {
+            Ok(exit) => {
+                info!("Attempt {}/{} of `{}` succeeded",
+                      count, policy.attempts, line);
+                return Ok(exit);
+            }
+            Err(error) => error
+        };
+        info!("Attempt {}/{} of `{}` failed: {}",
+              count, policy.attempts, line, error);
+        if count >= policy.attempts || !(policy.retry_on)(&error) ||
+                !sleep_unless_signaled(backoff) {
+            return Err(error);
+        }
+        count += 1;
+        backoff *= 2;
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright 2017 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cancellation::CancellationToken;
+use libc::c_int;
+use libc;
+use local_shell::LocalShell;
+use local_shell::current_shell;
+use local_shell::enter_shell;
+use local_shell::new_child_shell;
+use local_shell::wait_processes;
+use std::any::Any;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread::ScopedJoinHandle;
+use std::thread;
+
+/// Scope of `shell::scope()`, which can spawn threads borrowing local
+/// variables.
+pub struct Scope<'scope, 'env: 'scope> {
+    scope: &'scope thread::Scope<'scope, 'env>,
+    shell: Arc<Mutex<LocalShell>>,
+    /// Shells of the threads, kept until the cleanup as the threads' processes
+    /// outlive them if they are not waited.
+    thread_shells: Arc<Mutex<Vec<Arc<Mutex<LocalShell>>>>>,
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// Same as `shell::spawn()`, but the thread is joined at the end of the
+    /// scope and its shell belongs to the scope.
+    pub fn spawn<F, T>(&self, f: F) -> ScopedShellHandle<'scope, T>
+            where F: FnOnce() -> T + Send + 'scope, T: Send + 'scope {
+        let shell = new_child_shell(&self.shell);
+        self.thread_shells.lock().unwrap().push(shell.clone());
+        let shell_clone = shell.clone();
+        let join_handle = self.scope.spawn(move || {
+            let _guard = enter_shell(&shell_clone);
+            f()
+        });
+        ScopedShellHandle {
+            join_handle,
+            shell,
+        }
+    }
+
+    /// Sends a signal to every process started in the scope.
+    pub fn signal(&self, signal: c_int) {
+        self.shell.lock().unwrap().signal(signal);
+    }
+}
+
+/// Handle of a thread started by `Scope::spawn()`.
+pub struct ScopedShellHandle<'scope, T> {
+    join_handle: ScopedJoinHandle<'scope, T>,
+    shell: Arc<Mutex<LocalShell>>,
+}
+
+impl<'scope, T> ScopedShellHandle<'scope, T> {
+    pub fn signal(&self, signal: c_int) {
+        self.shell.lock().unwrap().signal(signal);
+    }
+
+    /// Returns the token cancelled when the thread's shell is signaled.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.shell.lock().unwrap().cancellation_token()
+    }
+
+    pub fn join(self) -> Result<T, Box<dyn Any + Send + 'static>> {
+        self.join_handle.join()
+    }
+}
+
+impl<'scope, T> Deref for ScopedShellHandle<'scope, T> {
+    type Target = ScopedJoinHandle<'scope, T>;
+    fn deref(&self) -> &Self::Target {
+        &self.join_handle
+    }
+}
+
+/// Terminates processes left in the scope when it is dropped.
+struct Cleanup {
+    shell: Arc<Mutex<LocalShell>>,
+    thread_shells: Arc<Mutex<Vec<Arc<Mutex<LocalShell>>>>>,
+}
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        self.shell.lock().unwrap().signal(libc::SIGTERM);
+        wait_all(&self.shell);
+        self.thread_shells.lock().unwrap().clear();
+    }
+}
+
+/// Waits processes left in `shell` and its descendant shells.
+fn wait_all(shell: &Arc<Mutex<LocalShell>>) {
+    wait_processes(shell);
+    let children = shell.lock().unwrap().children();
+    for child in &children {
+        wait_all(child);
+    }
+}
+
+/// Runs `f` on a new `LocalShell` nested in the current one, like
+/// `std::thread::scope()`.
+///
+/// Commands run by `f` on the current thread and by threads started with
+/// `Scope::spawn()` belong to the scope. When `f` returns or panics, the
+/// threads are joined, and then every process still registered in the scope
+/// receives SIGTERM and is waited.
+pub fn scope<'env, F, T>(f: F) -> T
+        where F: for<'scope> FnOnce(&Scope<'scope, 'env>) -> T {
+    let shell = new_child_shell(&current_shell());
+    let _guard = enter_shell(&shell);
+    // Dropped before the guard, after the threads are joined.
+    let cleanup = Cleanup {
+        shell: shell.clone(),
+        thread_shells: Arc::new(Mutex::new(Vec::new())),
+    };
+    thread::scope(|scope| {
+        f(&Scope {
+            scope,
+            shell: shell.clone(),
+            thread_shells: cleanup.thread_shells.clone(),
+        })
+    })
+}
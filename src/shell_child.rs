@@ -12,38 +12,148 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use capture::Transcript;
+use dry_run::DryRunChild;
+use errno::errno;
+use journal::Journal;
+use journal::JournalEntry;
 use libc::c_int;
 use libc;
 use local_shell::current_shell;
+use pty::Pty;
+use resource_usage::ResourceUsage;
 use result::ShellError;
 use result::ShellResult;
 use result::ShellResultExt;
+use result::SuccessPredicate;
 use result::check_errno;
+use result::from_status_if;
+use std::fmt;
+use std::io::BufReader;
+use std::io::Cursor;
 use std::io::Read;
+use std::io;
 use std::mem;
+use std::os::unix::process::ExitStatusExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::io::OwnedFd;
+use std::os::unix::io::RawFd;
 use std::process::Child;
 use std::process::Command;
+use std::process::ExitStatus;
+use std::ptr;
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::thread::JoinHandle;
+use std::time::Instant;
+use std::time::SystemTime;
+use stream::ShellLines;
+use subreaper::reap_child;
+use subreaper::spawn_child;
+use subreaper::unregister_child;
+use xtrace::XTrace;
+
+/// Error returned when stdout of a child is not available to the caller.
+pub fn stdout_not_piped() -> ShellError {
+    ShellError::from(io::Error::new(
+            io::ErrorKind::InvalidInput, "stdout is not piped"))
+}
+
+/// Error returned when stderr of a child is not available to the caller.
+pub fn stderr_not_piped() -> ShellError {
+    ShellError::from(io::Error::new(
+            io::ErrorKind::InvalidInput, "stderr is not piped"))
+}
+
+/// Opens a pidfd referring to `pid`.
+///
+/// Returns `None` if the kernel does not support pidfd (before Linux 5.3).
+fn pidfd_open(pid: u32) -> Option<OwnedFd> {
+    let fd = unsafe {
+        libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0)
+    };
+    if fd == -1 {
+        debug!("pidfd_open is not available {:?}", errno());
+        return None;
+    }
+    Some(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
 
-#[derive(Debug)]
 pub struct ShellChildCore {
     command_line: String,
     pub child: Child,
+    /// Keeps referring to the process after it is reaped, so signals cannot
+    /// reach a process reusing the same PID.
+    pidfd: Option<OwnedFd>,
+    /// Set once the process is reaped by `wait()`.
+    reaped: bool,
+    started: Instant,
+    /// Master side of the pseudo-terminal if `ShellCommand::pty()` is used.
+    pub pty: Option<Pty>,
+    /// Threads passing output to callbacks, joined in `wait()`.
+    pub readers: Vec<JoinHandle<()>>,
+    /// Thread recording output if `ShellCommand::capture_output()` is used.
+    pub capture: Option<JoinHandle<Result<Transcript, ShellError>>>,
+    /// Overrides `ExitStatus::success()` to decide the result of `wait()`.
+    pub success: Option<SuccessPredicate>,
+    /// Attaches resource usage to the result of `wait()`.
+    pub collect_usage: bool,
+    /// Traces the exit status in `wait()` if set.
+    pub xtrace: Option<XTrace>,
+    /// Entry written to the journal in `wait()`.
+    pub journal: Option<(Journal, JournalEntry)>,
+}
+
+impl fmt::Debug for ShellChildCore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ShellChildCore")
+            .field("command_line", &self.command_line)
+            .field("child", &self.child)
+            .field("pidfd", &self.pidfd)
+            .field("pty", &self.pty)
+            .field("xtrace", &self.xtrace)
+            .finish_non_exhaustive()
+    }
 }
 
 impl ShellChildCore {
     fn new(command_line: String, child: Child) -> ShellChildCore {
+        // The child is not reaped until `wait()`, so the PID still refers to
+        // it here.
+        let pidfd = pidfd_open(child.id());
         ShellChildCore {
             command_line: command_line,
             child: child,
+            pidfd,
+            reaped: false,
+            started: Instant::now(),
+            pty: None,
+            readers: Vec::new(),
+            capture: None,
+            success: None,
+            collect_usage: false,
+            xtrace: None,
+            journal: None,
         }
     }
 
-    pub fn signal(&self, sig: c_int) -> Result<(), ShellError> {
-        let kill_pid = self.child.id() as i32;
+    pub fn command_line(&self) -> &str {
+        &self.command_line
+    }
 
+    pub fn signal(&self, sig: c_int) -> Result<(), ShellError> {
         info!("Sending signal {} to {}", sig, self.child.id());
+        if let Some(ref pidfd) = self.pidfd {
+            unsafe {
+                check_errno("pidfd_send_signal", libc::syscall(
+                        libc::SYS_pidfd_send_signal, pidfd.as_raw_fd(), sig,
+                        ptr::null::<libc::siginfo_t>(), 0) as c_int)?;
+            }
+            return Ok(());
+        }
+
+        let kill_pid = self.child.id() as i32;
         unsafe {
             check_errno("kill", libc::kill(kill_pid, sig))?;
         }
@@ -51,8 +161,27 @@ impl ShellChildCore {
     }
 
     pub fn wait_null(&self) -> Result<(), ShellError> {
+        if let Some(ref pidfd) = self.pidfd {
+            // pidfd becomes readable when the process exits.
+            let mut pollfd = libc::pollfd {
+                fd: pidfd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            loop {
+                let result = unsafe {
+                    libc::poll(&mut pollfd as *mut libc::pollfd, 1, -1)
+                };
+                if result == -1 && errno().0 == libc::EINTR {
+                    continue;
+                }
+                check_errno("poll", result)?;
+                return Ok(());
+            }
+        }
+
         unsafe {
-            let mut info = mem::uninitialized::<libc::siginfo_t>();
+            let mut info = mem::zeroed::<libc::siginfo_t>();
             check_errno("waitid",
                         libc::waitid(
                             libc::P_PID,
@@ -63,8 +192,88 @@ impl ShellChildCore {
         Ok(())
     }
 
+    /// Reaps the process by `wait4` to collect its resource usage.
+    fn wait4(&mut self) -> Result<(ExitStatus, ResourceUsage), ShellError> {
+        // Same as `Child::wait()`, close stdin so that the process does not
+        // wait for input forever.
+        drop(self.child.stdin.take());
+        let pid = self.child.id() as libc::pid_t;
+        let mut status: c_int = 0;
+        let mut usage = unsafe { mem::zeroed::<libc::rusage>() };
+        self.wait_null()?;
+        reap_child(pid, || loop {
+            let result = unsafe {
+                libc::wait4(pid, &mut status as *mut c_int, 0,
+                            &mut usage as *mut libc::rusage)
+            };
+            if result == -1 && errno().0 == libc::EINTR {
+                continue;
+            }
+            return check_errno("wait4", result);
+        })?;
+        self.reaped = true;
+        let usage = ResourceUsage::new(self.started.elapsed(), &usage);
+        Ok((ExitStatus::from_raw(status), usage))
+    }
+
     pub fn wait(mut self) -> ShellResult {
-        ShellResult::from_status(self.command_line, self.child.wait()?)
+        let (status, usage) = match self.wait4() {
+            Ok(result) => result,
+            Err(error) => {
+                if let Some((journal, mut entry)) = self.journal.take() {
+                    entry.error = Some(error.to_string());
+                    journal.write(&entry);
+                }
+                return Err(error);
+            }
+        };
+        for reader in self.readers.drain(..) {
+            if reader.join().is_err() {
+                error!("Output callback of {} panicked", self.command_line);
+            }
+        }
+        debug!("{} exited with {:?} {:?}", self.command_line, status, usage);
+        if let Some(ref xtrace) = self.xtrace {
+            xtrace.exited(&self.command_line, &status, usage.wall_time);
+        }
+        if let Some((journal, mut entry)) = self.journal.take() {
+            entry.end = Some(SystemTime::now());
+            entry.status = Some(status);
+            entry.usage = Some(usage);
+            journal.write(&entry);
+        }
+        let line = mem::take(&mut self.command_line);
+        let result = match self.success {
+            Some(ref success) => from_status_if(line, status, &**success),
+            None => ShellResult::from_status(line, status),
+        };
+        let result = match result {
+            Ok(exit) if self.collect_usage => Ok(exit.with_usage(usage)),
+            Err(error) if self.collect_usage =>
+                Err(ShellError::WithUsage(Box::new(error), usage)),
+            result => result,
+        };
+        match self.capture.take() {
+            Some(capture) => {
+                let transcript = capture.join()
+                    .expect("Capture thread panicked")?;
+                match result {
+                    Ok(exit) => Ok(exit.with_transcript(transcript)),
+                    Err(error) => Err(ShellError::WithTranscript(
+                            Box::new(error), transcript)),
+                }
+            }
+            None => result
+        }
+    }
+}
+
+/// Leaves a process dropped without wait to the subreaper.
+impl Drop for ShellChildCore {
+    fn drop(&mut self) {
+        if !self.reaped {
+            unregister_child(self.child.id() as libc::pid_t);
+        }
     }
 }
 
@@ -83,7 +292,23 @@ impl ShellChildCore {
 pub type ShellChildArc = Arc<RwLock<Option<ShellChildCore>>>;
 
 /// This wraps `ShellChildArc` and provides helper functions.
-pub struct ShellChild(pub ShellChildArc);
+///
+/// Fields are private because a child in dry-run mode has no process and
+/// reports a fake result instead. `process()` returns the wrapped
+/// `ShellChildArc`, and `ShellChild::from()` wraps one.
+pub struct ShellChild {
+    process: ShellChildArc,
+    dry_run: Option<DryRunChild>,
+}
+
+impl From<ShellChildArc> for ShellChild {
+    fn from(process: ShellChildArc) -> ShellChild {
+        ShellChild {
+            process,
+            dry_run: None,
+        }
+    }
+}
 
 impl ShellChild {
     pub fn new(line: String, mut command: Command)
@@ -93,49 +318,153 @@ impl ShellChild {
         if lock.signaled() {
             return Err(ShellError::from_signal(line, 101))
         }
-        let child = command.spawn()?;
+        let child = spawn_child(&mut command)?;
         let process = Arc::new(RwLock::new(
                 Some(ShellChildCore::new(line, child))));
         lock.add_process(&process);
-        Ok(ShellChild(process))
+        Ok(ShellChild::from(process))
+    }
+
+    /// Creates a child which does not run any process.
+    pub fn dry_run(child: DryRunChild) -> ShellChild {
+        ShellChild {
+            process: Arc::new(RwLock::new(None)),
+            dry_run: Some(child),
+        }
+    }
+
+    /// Returns the process, which is `None` after `wait()` or in dry-run mode.
+    pub fn process(&self) -> &ShellChildArc {
+        &self.process
     }
 
     /// Sends a signal to the process.
     pub fn signal(&self, signal: c_int) -> Result<(), ShellError> {
-        let process = &self.0;
-        let process = process.read().unwrap();
+        if self.dry_run.is_some() {
+            return Ok(());
+        }
+        let process = self.process.read().unwrap();
         process.as_ref().ok_or(ShellError::NoSuchProcess)?.signal(signal)
     }
 
     /// Waits for termination of the process.
-    pub fn wait(self) -> ShellResult {
+    pub fn wait(mut self) -> ShellResult {
+        if let Some(child) = self.dry_run.take() {
+            return child.wait();
+        }
         {
-            let data = self.0.read().unwrap();
+            let data = self.process.read().unwrap();
             data.as_ref().ok_or(ShellError::NoSuchProcess)?.wait_null()?;
         }
-        let result = {
-            let mut data = self.0.write().unwrap();
-            data.take().ok_or(ShellError::NoSuchProcess)
-                .and_then(|c| c.wait())
-        };
+        // Reader threads can outlive the process, so the lock is released
+        // before joining them.
+        let core = self.process.write().unwrap().take();
+        let result = core.ok_or(ShellError::NoSuchProcess)
+            .and_then(|c| c.wait());
         {
             let shell = current_shell();
             let mut lock = shell.lock().unwrap();
-            lock.remove_process(&self.0);
+            lock.remove_process(&self.process);
         }
         result
     }
 
+    /// Returns the master side of the pseudo-terminal allocated by
+    /// `ShellCommand::pty()`.
+    pub fn pty(&self) -> Result<Pty, ShellError> {
+        let lock = self.process.read().unwrap();
+        let core = lock.as_ref().ok_or(ShellError::NoSuchProcess)?;
+        match core.pty {
+            Some(ref pty) => pty.try_clone(),
+            None => Err(ShellError::from(io::Error::new(
+                        io::ErrorKind::NotFound, "No pty is allocated"))),
+        }
+    }
+
+    /// Returns an iterator over lines of stdout. stdout must be piped, or the
+    /// command must run on a pty.
+    pub fn lines(self) -> Result<ShellLines, ShellError> {
+        if let Some(stdout) = self.dry_run.as_ref()
+                .map(|child| child.stdout().to_string()) {
+            return Ok(ShellLines::from_reader(self, Cursor::new(stdout)));
+        }
+        let (stdout, pty) = {
+            let mut lock = self.process.write().unwrap();
+            let lock = lock.as_mut().ok_or(ShellError::NoSuchProcess)?;
+            let pty = match lock.pty {
+                Some(ref pty) => Some(pty.try_clone()?),
+                None => None,
+            };
+            (lock.child.stdout.take(), pty)
+        };
+        match (stdout, pty) {
+            (Some(stdout), _) => Ok(ShellLines::new(self, stdout)),
+            (None, Some(pty)) =>
+                Ok(ShellLines::from_reader(self, BufReader::new(pty))),
+            (None, None) => Err(stdout_not_piped()),
+        }
+    }
+
     /// Obtains stdout as utf8 string.
     /// Returns Err if it returns non-zero exit code.
+    ///
+    /// If the command runs on a pty, reads the output from the pty instead.
     pub fn stdout_utf8(self) -> Result<String, ShellError> {
         let mut string = String::new();
-        {
-            let mut lock = self.0.write().unwrap();
-            let lock = lock.as_mut().ok_or(ShellError::NoSuchProcess)?;
-            lock.child.stdout.as_mut().unwrap().read_to_string(&mut string)?;
+        if let Some(ref child) = self.dry_run {
+            string.push_str(child.stdout());
+        } else {
+            // Reads without the lock, so that the process can be signaled.
+            let (stdout, pty) = {
+                let mut lock = self.process.write().unwrap();
+                let lock = lock.as_mut().ok_or(ShellError::NoSuchProcess)?;
+                let pty = match lock.pty {
+                    Some(ref pty) => Some(pty.try_clone()?),
+                    None => None,
+                };
+                (lock.child.stdout.take(), pty)
+            };
+            match (stdout, pty) {
+                (Some(mut stdout), _) => stdout.read_to_string(&mut string)?,
+                (None, Some(mut pty)) => pty.read_to_string(&mut string)?,
+                (None, None) => return Err(stdout_not_piped()),
+            };
         }
         self.wait()?;
         Ok(string)
     }
 }
+
+/// Records a process dropped without `wait()` in the journal.
+impl Drop for ShellChild {
+    fn drop(&mut self) {
+        // Ignores a poisoned lock so as not to panic while unwinding.
+        let mut lock = match self.process.write() {
+            Ok(lock) => lock,
+            Err(_) => return,
+        };
+        let journal = lock.as_mut().and_then(|core| core.journal.take());
+        if let Some((journal, mut entry)) = journal {
+            entry.error = Some(String::from("Dropped without wait"));
+            journal.write(&entry);
+        }
+    }
+}
+
+#[test]
+fn test_pidfd_signal_after_reaped() {
+    let child = Command::new("true").spawn().unwrap();
+    let pid = child.id() as libc::pid_t;
+    let core = ShellChildCore::new(String::from("true"), child);
+    core.wait_null().unwrap();
+    unsafe {
+        assert_eq!(libc::waitpid(pid, ptr::null_mut(), 0), pid);
+    }
+    // Kernels older than 5.3 do not support pidfd.
+    if core.pidfd.is_none() {
+        return;
+    }
+    // The PID may be reused by now, but the pidfd still refers to the
+    // reaped process.
+    assert!(core.signal(libc::SIGTERM).is_err());
+}
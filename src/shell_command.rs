@@ -12,36 +12,990 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use capture::spawn_capture;
+use dry_run::DryRunChild;
+use dry_run::dry_run;
+use dry_run::quote;
+use errno::Errno;
+use errno::set_errno;
+use journal::JournalEntry;
+use journal::journal;
+use libc::c_int;
+use libc;
+use local_shell::current_shell;
+use pool::Pool;
+use pty::open_pty;
 use shell_child::ShellChild;
+use signal_trap::trapped_signals;
+use shell_child::stderr_not_piped;
+use shell_child::stdout_not_piped;
 use result::ShellResult;
 use result::ShellError;
+use result::SuccessPredicate;
+use retry::RetryPolicy;
+use retry::retry;
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::path::PathBuf;
 use std::process::Command;
+use std::process::ExitStatus;
 use std::process::Stdio;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::SystemTime;
+use stream::LineCallback;
+use stream::line_output_callback;
+use stream::ShellLines;
+use stream::spawn_line_reader;
+use tee::Passthrough;
+use tee::TeeTarget;
+use tee::chain_callbacks;
+use tee::tee_callback;
+use user::lookup_user;
+use xargs::aggregate;
+use xargs::exec_size;
+use xargs::split_args;
+use xtrace::xtrace;
 
+static DIE_WITH_PARENT: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether commands created after the call are killed when the parent
+/// process dies. See `ShellCommand::die_with_parent()`.
+pub fn set_die_with_parent_default(enabled: bool) {
+    DIE_WITH_PARENT.store(enabled, Ordering::SeqCst);
+}
+
+/// Type of the resource argument of `setrlimit`, e.g. `libc::RLIMIT_CPU`.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+pub type RlimitResource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+pub type RlimitResource = c_int;
+
+const IOPRIO_WHO_PROCESS: c_int = 1;
+const IOPRIO_CLASS_SHIFT: c_int = 13;
+const IOPRIO_MAX_LEVEL: u8 = 7;
+
+/// I/O scheduling class and priority passed to `ioprio_set`.
+///
+/// Priority levels range from 0 (highest) to 7 (lowest).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    RealTime(u8),
+    BestEffort(u8),
+    Idle,
+}
+
+impl IoPriority {
+    fn value(&self) -> c_int {
+        let (class, level) = match *self {
+            IoPriority::RealTime(level) => (1, level),
+            IoPriority::BestEffort(level) => (2, level),
+            IoPriority::Idle => (3, 0),
+        };
+        class << IOPRIO_CLASS_SHIFT | level as c_int
+    }
+}
+
+/// How stdin, stdout or stderr of a command is connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StdioSpec {
+    Inherit,
+    Piped,
+    Null,
+}
+
+impl StdioSpec {
+    fn to_stdio(self) -> Stdio {
+        match self {
+            StdioSpec::Inherit => Stdio::inherit(),
+            StdioSpec::Piped => Stdio::piped(),
+            StdioSpec::Null => Stdio::null(),
+        }
+    }
+}
+
+/// Function applied to every `Command` built by `ShellCommand::command()`.
+type CommandHook = Arc<dyn Fn(&mut Command) + Send + Sync>;
+
+/// Returns true if both are `None` or point to the same value.
+fn same_arc<T: ?Sized>(a: &Option<Arc<T>>, b: &Option<Arc<T>>) -> bool {
+    match (a.as_ref(), b.as_ref()) {
+        (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Description of a command.
+///
+/// It builds a new `std::process::Command` every time it is spawned, so it can
+/// be cloned and run multiple times.
+#[derive(Clone)]
 pub struct ShellCommand {
     line: String,
-    pub command: Command,
+    program: OsString,
+    args: Vec<OsString>,
+    /// Environment variables to set, or to remove if `None`.
+    env: BTreeMap<OsString, Option<OsString>>,
+    env_clear: bool,
+    current_dir: Option<PathBuf>,
+    stdin: Option<StdioSpec>,
+    stdout: Option<StdioSpec>,
+    stderr: Option<StdioSpec>,
+    die_with_parent: bool,
+    rlimits: Vec<(RlimitResource, libc::rlim_t, libc::rlim_t)>,
+    nice: Option<c_int>,
+    umask: Option<libc::mode_t>,
+    ioprio: Option<IoPriority>,
+    uid: Option<libc::uid_t>,
+    gid: Option<libc::gid_t>,
+    groups: Option<Vec<libc::gid_t>>,
+    /// User name shown in the command line instead of the uid.
+    user_name: Option<String>,
+    /// Rows and columns of the pty.
+    pty: Option<(u16, u16)>,
+    stdout_callback: Option<LineCallback>,
+    stderr_callback: Option<LineCallback>,
+    stdout_tee: Option<TeeTarget>,
+    stderr_tee: Option<TeeTarget>,
+    tee_prefix: Option<String>,
+    capture_output: bool,
+    collect_usage: bool,
+    success: Option<SuccessPredicate>,
+    retry: Option<RetryPolicy>,
+    hooks: Vec<CommandHook>,
 }
 
 impl ShellCommand {
+    /// Creates a command from the program, arguments, environment and working
+    /// directory of `command`.
+    ///
+    /// `Command` does not expose `env_clear()` and stdio, so they are not
+    /// taken over. Set them by `env_clear()`, `stdin()`, `stdout()` and
+    /// `stderr()`, or by `configure()` for anything else.
     pub fn new(line: String, command: Command) -> ShellCommand {
         ShellCommand {
             line: line,
-            command: command,
+            program: command.get_program().to_os_string(),
+            args: command.get_args().map(|arg| arg.to_os_string()).collect(),
+            env: command.get_envs()
+                .map(|(key, value)| {
+                    (key.to_os_string(), value.map(|v| v.to_os_string()))
+                })
+                .collect(),
+            env_clear: false,
+            current_dir: command.get_current_dir().map(|dir| dir.to_path_buf()),
+            stdin: None,
+            stdout: None,
+            stderr: None,
+            die_with_parent: DIE_WITH_PARENT.load(Ordering::SeqCst),
+            rlimits: Vec::new(),
+            nice: None,
+            umask: None,
+            ioprio: None,
+            uid: None,
+            gid: None,
+            groups: None,
+            user_name: None,
+            pty: None,
+            stdout_callback: None,
+            stderr_callback: None,
+            stdout_tee: None,
+            stderr_tee: None,
+            tee_prefix: None,
+            capture_output: false,
+            collect_usage: false,
+            success: None,
+            retry: None,
+            hooks: Vec::new(),
         }
     }
 
-    pub fn run(self) -> ShellResult {
-        self.spawn().and_then(|job| job.wait())
+    pub fn arg<S: AsRef<OsStr>>(mut self, arg: S) -> ShellCommand {
+        self.line.push(' ');
+        self.line.push_str(&quote(arg.as_ref()));
+        self.args.push(arg.as_ref().to_os_string());
+        self
+    }
+
+    pub fn args<I, S>(self, args: I) -> ShellCommand
+            where I: IntoIterator<Item = S>, S: AsRef<OsStr> {
+        args.into_iter().fold(self, |command, arg| command.arg(arg))
+    }
+
+    pub fn env<K, V>(mut self, key: K, value: V) -> ShellCommand
+            where K: AsRef<OsStr>, V: AsRef<OsStr> {
+        self.env.insert(key.as_ref().to_os_string(),
+                        Some(value.as_ref().to_os_string()));
+        self
+    }
+
+    pub fn env_remove<K: AsRef<OsStr>>(mut self, key: K) -> ShellCommand {
+        self.env.insert(key.as_ref().to_os_string(), None);
+        self
+    }
+
+    /// Clears the environment inherited from the current process.
+    pub fn env_clear(mut self) -> ShellCommand {
+        self.env.clear();
+        self.env_clear = true;
+        self
+    }
+
+    pub fn current_dir<P: AsRef<Path>>(mut self, dir: P) -> ShellCommand {
+        self.current_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn stdin(mut self, stdin: StdioSpec) -> ShellCommand {
+        self.stdin = Some(stdin);
+        self
+    }
+
+    pub fn stdout(mut self, stdout: StdioSpec) -> ShellCommand {
+        self.stdout = Some(stdout);
+        self
+    }
+
+    pub fn stderr(mut self, stderr: StdioSpec) -> ShellCommand {
+        self.stderr = Some(stderr);
+        self
+    }
+
+    /// Makes the child process receive SIGKILL when the parent dies.
+    ///
+    /// It uses `PR_SET_PDEATHSIG`, which is Linux specific. Note that the
+    /// kernel sends the signal when the thread that spawned the command exits,
+    /// not only when the whole process exits.
+    pub fn die_with_parent(mut self) -> ShellCommand {
+        self.die_with_parent = true;
+        self
+    }
+
+    /// Sets soft and hard limits of `resource` in the child process.
+    ///
+    /// When the process is killed for exceeding a limit (e.g. SIGXCPU for
+    /// `libc::RLIMIT_CPU`), the `ShellError` describes the limit.
+    pub fn rlimit(mut self, resource: RlimitResource, soft: libc::rlim_t,
+                  hard: libc::rlim_t) -> ShellCommand {
+        self.rlimits.push((resource, soft, hard));
+        self
+    }
+
+    /// Adds `increment` to the niceness of the child process, as nice(1).
+    pub fn nice(mut self, increment: c_int) -> ShellCommand {
+        self.nice = Some(increment);
+        self
+    }
+
+    /// Sets the file mode creation mask of the child process.
+    pub fn umask(mut self, mode: libc::mode_t) -> ShellCommand {
+        self.umask = Some(mode);
+        self
+    }
+
+    /// Sets the I/O scheduling class and priority of the child process.
+    /// Fails if the priority level is out of 0 to 7. Linux only.
+    pub fn ioprio(mut self, priority: IoPriority)
+            -> Result<ShellCommand, ShellError> {
+        match priority {
+            IoPriority::RealTime(level) | IoPriority::BestEffort(level)
+                    if level > IOPRIO_MAX_LEVEL =>
+                return Err(ShellError::IoError(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("I/O priority level {} is out of 0 to {}",
+                                level, IOPRIO_MAX_LEVEL)))),
+            _ => {}
+        }
+        self.ioprio = Some(priority);
+        Ok(self)
     }
 
-    pub fn spawn(self) -> Result<ShellChild, ShellError> {
-        ShellChild::new(self.line, self.command)
+    /// Runs the command with `uid`.
+    ///
+    /// If the current process runs as root and `groups()` is not specified,
+    /// supplementary groups are cleared.
+    pub fn uid(mut self, uid: libc::uid_t) -> ShellCommand {
+        self.uid = Some(uid);
+        self
     }
 
-    pub fn stdout_utf8(mut self) -> Result<String, ShellError> {
-        self.command.stdout(Stdio::piped());
-        self.spawn()?.stdout_utf8()
+    /// Runs the command with `gid`.
+    pub fn gid(mut self, gid: libc::gid_t) -> ShellCommand {
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Sets supplementary groups of the command.
+    pub fn groups(mut self, groups: &[libc::gid_t]) -> ShellCommand {
+        self.groups = Some(groups.to_vec());
+        self
+    }
+
+    /// Runs the command as the user `name` with the user's primary and
+    /// supplementary groups.
+    pub fn as_user(mut self, name: &str) -> Result<ShellCommand, ShellError> {
+        let user = lookup_user(name)?;
+        self.uid = Some(user.uid);
+        self.gid = Some(user.gid);
+        self.groups = Some(user.groups);
+        self.user_name = Some(String::from(name));
+        Ok(self)
+    }
+
+    /// Runs the command on a newly allocated pseudo-terminal.
+    ///
+    /// stdin, stdout and stderr of the command are connected to the pty, which
+    /// becomes the controlling terminal of a new session. The master side is
+    /// available by `ShellChild::pty()`, and `stdout_utf8()` reads output from
+    /// it.
+    pub fn pty(mut self, rows: u16, cols: u16) -> ShellCommand {
+        self.pty = Some((rows, cols));
+        self
+    }
+
+    /// Calls `callback` with each line of stdout while the command runs.
+    ///
+    /// Lines are read by a background thread, and `ShellChild::wait()`
+    /// returns after all lines are passed to the callback. `spawn()` fails if
+    /// it is combined with `pty()`.
+    pub fn on_stdout_line<F>(mut self, callback: F) -> ShellCommand
+            where F: FnMut(&str) + Send + 'static {
+        self.stdout_callback = Some(Arc::new(Mutex::new(callback)));
+        self
+    }
+
+    /// Calls `callback` with each line of stderr while the command runs.
+    pub fn on_stderr_line<F>(mut self, callback: F) -> ShellCommand
+            where F: FnMut(&str) + Send + 'static {
+        self.stderr_callback = Some(Arc::new(Mutex::new(callback)));
+        self
+    }
+
+    /// Passes stdout through to the stdout of the current process and also
+    /// copies it to `target`, which is a path, a `File` or a `TeeBuffer`.
+    pub fn tee_stdout<T: Into<TeeTarget>>(mut self, target: T)
+            -> ShellCommand {
+        self.stdout_tee = Some(target.into());
+        self
+    }
+
+    /// Passes stderr through to the stderr of the current process and also
+    /// copies it to `target`.
+    pub fn tee_stderr<T: Into<TeeTarget>>(mut self, target: T)
+            -> ShellCommand {
+        self.stderr_tee = Some(target.into());
+        self
+    }
+
+    /// Prepends `prefix` to each line of teed output, e.g. `"[build] "`.
+    pub fn tee_prefix(mut self, prefix: &str) -> ShellCommand {
+        self.tee_prefix = Some(String::from(prefix));
+        self
+    }
+
+    /// Records stdout and stderr in the order they are produced.
+    ///
+    /// The `Transcript` is available from the `ShellResult` on success and
+    /// from `ShellError::transcript()` on failure. Output consumed by line
+    /// callbacks or tee is not recorded.
+    pub fn capture_output(mut self) -> ShellCommand {
+        self.capture_output = true;
+        self
+    }
+
+    /// Attaches the resources consumed by the command to the result, which
+    /// is available from `SuccessfulExit::usage()` on success and from
+    /// `ShellError::usage()` on failure.
+    pub fn collect_usage(mut self) -> ShellCommand {
+        self.collect_usage = true;
+        self
+    }
+
+    /// Treats the exit codes in `codes` as success, e.g. `&[0, 1]` for grep.
+    ///
+    /// The actual status is still available by `ShellResultExt::status()`.
+    pub fn ok_codes(self, codes: &[i32]) -> ShellCommand {
+        let codes = codes.to_vec();
+        self.success_if(move |status| match status.code() {
+            Some(code) => codes.contains(&code),
+            None => false,
+        })
+    }
+
+    /// Decides whether the command succeeded by `predicate` instead of
+    /// `ExitStatus::success()`.
+    pub fn success_if<F>(mut self, predicate: F) -> ShellCommand
+            where F: Fn(&ExitStatus) -> bool + Send + Sync + 'static {
+        self.success = Some(Arc::new(predicate));
+        self
+    }
+
+    /// Calls `hook` with every `Command` built from the description, after
+    /// the other settings are applied.
+    ///
+    /// This allows settings which `ShellCommand` does not describe, e.g.
+    /// redirecting stdout to a file or to a pipe of another process. The hook
+    /// runs once per spawn, so it should duplicate such file descriptors.
+    pub fn configure<F>(mut self, hook: F) -> ShellCommand
+            where F: Fn(&mut Command) + Send + Sync + 'static {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Makes `run()` retry the command according to `policy`.
+    ///
+    /// Each attempt spawns a clone of the command and is logged with its exit
+    /// status. Retrying stops once the current `LocalShell` is signaled.
+    pub fn retry(mut self, policy: RetryPolicy) -> ShellCommand {
+        self.retry = Some(policy);
+        self
+    }
+
+    fn switches_user(&self) -> bool {
+        self.uid.is_some() || self.gid.is_some() || self.groups.is_some()
+    }
+
+    /// Returns the user and group the command runs as if they are switched.
+    fn user_prefix(&self) -> Option<String> {
+        Some(match (self.user_name.as_ref(), self.uid, self.gid) {
+            (Some(name), _, _) => format!("[{}]", name),
+            (None, Some(uid), Some(gid)) => format!("[{}:{}]", uid, gid),
+            (None, Some(uid), None) => format!("[{}]", uid),
+            (None, None, Some(gid)) => format!("[:{}]", gid),
+            (None, None, None) => return None,
+        })
+    }
+
+    /// Returns the command line, prefixed by the user and group if they are
+    /// switched.
+    fn command_line(&self) -> String {
+        match self.user_prefix() {
+            Some(user) => format!("{} {}", user, self.line),
+            None => self.line.clone(),
+        }
+    }
+
+    /// Returns the command line as it runs on the current `LocalShell`: in its
+    /// working directory, as the switched user and with the environment
+    /// variables exported on the shell or set on the command, e.g.
+    /// `(cd /srv && [nobody] X=1 rm -rf build)`. Words are quoted for a POSIX
+    /// shell by `cmd!` and `arg()`.
+    pub fn quoted_line(&self) -> String {
+        let mut words: Vec<String> = self.user_prefix().into_iter().collect();
+        let env = self.env_overlay();
+        let removed = env.iter().filter(|&(_, value)| value.is_none());
+        if self.env_clear || removed.clone().next().is_some() {
+            words.push("env".to_string());
+            if self.env_clear {
+                words.push("-i".to_string());
+            }
+            for (key, _) in removed {
+                words.push(format!("-u {}", quote(key)));
+            }
+        }
+        for (key, value) in &env {
+            if let Some(ref value) = *value {
+                words.push(format!("{}={}", key.to_string_lossy(), quote(value)));
+            }
+        }
+        words.push(self.line.clone());
+        let line = words.join(" ");
+        match self.working_dir() {
+            Some(dir) => format!("(cd {} && {})", quote(dir.as_os_str()), line),
+            None => line,
+        }
+    }
+
+    /// Returns environment changes of the command on top of the ones exported
+    /// on the current `LocalShell`.
+    fn env_overlay(&self) -> BTreeMap<OsString, Option<OsString>> {
+        let mut env = if self.env_clear {
+            BTreeMap::new()
+        } else {
+            current_shell().lock().unwrap().env().clone()
+        };
+        env.extend(self.env.clone());
+        env
+    }
+
+    /// Returns the working directory resolved against the one of the current
+    /// `LocalShell`.
+    fn working_dir(&self) -> Option<PathBuf> {
+        let cwd = current_shell().lock().unwrap().cwd();
+        match (self.current_dir.as_ref(), cwd) {
+            (Some(dir), Some(cwd)) => Some(cwd.join(dir)),
+            (Some(dir), None) => Some(dir.clone()),
+            (None, cwd) => cwd,
+        }
+    }
+
+    /// Returns the journal entry of the command before it is spawned.
+    fn journal_entry(&self) -> JournalEntry {
+        let string = |s: &OsStr| s.to_string_lossy().into_owned();
+        let cwd = self.working_dir().or_else(|| env::current_dir().ok());
+        let thread = thread::current();
+        JournalEntry {
+            argv: Some(self.program.as_os_str()).into_iter()
+                .chain(self.args.iter().map(|arg| arg.as_os_str()))
+                .map(string).collect(),
+            env: self.env_overlay().iter()
+                .map(|(key, value)| {
+                    (string(key), value.as_ref().map(|v| string(v)))
+                })
+                .collect(),
+            env_clear: self.env_clear,
+            cwd: cwd.map(|cwd| cwd.to_string_lossy().into_owned()),
+            pid: 0,
+            thread: match thread.name() {
+                Some(name) => name.to_string(),
+                None => format!("{:?}", thread.id()),
+            },
+            start: SystemTime::now(),
+            end: None,
+            status: None,
+            usage: None,
+            error: None,
+        }
+    }
+
+    pub fn run(mut self) -> ShellResult {
+        // Failures are fake in dry-run mode, so they are not retried.
+        let retry_policy = self.retry.take().filter(|_| dry_run().is_none());
+        match retry_policy {
+            Some(policy) => {
+                let line = self.command_line();
+                retry(&policy, &line, || {
+                    self.clone().spawn().and_then(|job| job.wait())
+                })
+            }
+            None => self.spawn().and_then(|job| job.wait())
+        }
+    }
+
+    /// Returns bytes of `ARG_MAX` taken by the command line and the
+    /// environment the command would be spawned with.
+    fn exec_size(&self) -> usize {
+        let mut env = if self.env_clear {
+            BTreeMap::new()
+        } else {
+            env::vars_os().map(|(key, value)| (key, Some(value))).collect()
+        };
+        env.extend(self.env_overlay());
+        let env_size = env.iter()
+            .filter_map(|(key, value)| value.as_ref().map(|value| {
+                exec_size(key) + exec_size(value)
+            }))
+            .sum::<usize>();
+        self.args.iter().fold(exec_size(&self.program) + env_size,
+                              |size, arg| size + exec_size(arg))
+    }
+
+    /// Splits `args` into batches small enough for a command line.
+    ///
+    /// The command line of each batch shows the number of arguments instead of
+    /// the arguments, which would make logs and errors as large as `ARG_MAX`.
+    fn xargs_commands<I, S>(&self, args: I) -> Vec<ShellCommand>
+            where I: IntoIterator<Item = S>, S: AsRef<OsStr> {
+        let args = args.into_iter().map(|arg| arg.as_ref().to_os_string());
+        split_args(args, self.exec_size()).into_iter()
+            .map(|batch| {
+                let mut command = self.clone();
+                command.line = format!("{} <{} args>", self.line, batch.len());
+                command.args.extend(batch);
+                command
+            })
+            .collect()
+    }
+
+    /// Runs the command with `args` appended, like `xargs`.
+    ///
+    /// Arguments are split into multiple runs so that each command line fits
+    /// in `sysconf(_SC_ARG_MAX)` together with the environment. Every batch
+    /// runs even if one fails, and the first failure is returned. Nothing runs
+    /// if `args` is empty.
+    pub fn xargs<I, S>(self, args: I) -> ShellResult
+            where I: IntoIterator<Item = S>, S: AsRef<OsStr> {
+        let results = self.xargs_commands(args).into_iter()
+            .map(|command| command.run())
+            .collect::<Vec<_>>();
+        aggregate(results)
+    }
+
+    /// Same as `xargs()`, but runs up to `max_jobs` batches in parallel.
+    pub fn xargs_parallel<I, S>(self, args: I, max_jobs: usize) -> ShellResult
+            where I: IntoIterator<Item = S>, S: AsRef<OsStr> {
+        aggregate(Pool::new(max_jobs).run(self.xargs_commands(args)))
+    }
+
+    /// Builds `std::process::Command` from the description.
+    ///
+    /// Settings applied in the child process before exec (e.g. `rlimit()`,
+    /// `uid()`) are included, but `pty()`, output callbacks and capture are
+    /// handled by `spawn()`.
+    pub fn command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+        if self.env_clear {
+            command.env_clear();
+        }
+        for (key, value) in &self.env_overlay() {
+            match *value {
+                Some(ref value) => command.env(key, value),
+                None => command.env_remove(key),
+            };
+        }
+        if let Some(dir) = self.working_dir() {
+            command.current_dir(dir);
+        }
+        if let Some(stdin) = self.stdin {
+            command.stdin(stdin.to_stdio());
+        }
+        if let Some(stdout) = self.stdout {
+            command.stdout(stdout.to_stdio());
+        }
+        if let Some(stderr) = self.stderr {
+            command.stderr(stderr.to_stdio());
+        }
+        self.setup_child(&mut command);
+        for hook in &self.hooks {
+            hook(&mut command);
+        }
+        command
+    }
+
+    /// Registers functions running in the child process before exec.
+    fn setup_child(&self, command: &mut Command) {
+        let signals = trapped_signals();
+        if !signals.is_empty() {
+            unsafe {
+                command.pre_exec(move || {
+                    let mut sigset = mem::zeroed::<libc::sigset_t>();
+                    libc::sigemptyset(&mut sigset as *mut libc::sigset_t);
+                    for &signal in &signals {
+                        libc::sigaddset(
+                            &mut sigset as *mut libc::sigset_t, signal);
+                    }
+                    let result = libc::pthread_sigmask(
+                        libc::SIG_UNBLOCK, &sigset as *const libc::sigset_t,
+                        ptr::null_mut());
+                    if result != 0 {
+                        return Err(io::Error::from_raw_os_error(result));
+                    }
+                    Ok(())
+                });
+            }
+        }
+        if self.pty.is_some() {
+            unsafe {
+                command.pre_exec(|| {
+                    if libc::setsid() == -1 ||
+                            libc::ioctl(0, libc::TIOCSCTTY, 0) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        for &(resource, soft, hard) in &self.rlimits {
+            unsafe {
+                command.pre_exec(move || {
+                    let limit = libc::rlimit {
+                        rlim_cur: soft,
+                        rlim_max: hard,
+                    };
+                    if libc::setrlimit(resource, &limit) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        if let Some(increment) = self.nice {
+            unsafe {
+                command.pre_exec(move || {
+                    // nice() can return -1 on success.
+                    set_errno(Errno(0));
+                    if libc::nice(increment) == -1 {
+                        let error = io::Error::last_os_error();
+                        if error.raw_os_error() != Some(0) {
+                            return Err(error);
+                        }
+                    }
+                    Ok(())
+                });
+            }
+        }
+        if let Some(mode) = self.umask {
+            unsafe {
+                command.pre_exec(move || {
+                    libc::umask(mode);
+                    Ok(())
+                });
+            }
+        }
+        if let Some(priority) = self.ioprio {
+            let value = priority.value();
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS,
+                                     0, value) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        if self.switches_user() {
+            let uid = self.uid;
+            let gid = self.gid;
+            let groups = self.groups.clone();
+            unsafe {
+                command.pre_exec(move || {
+                    let result = match groups {
+                        Some(ref groups) => libc::setgroups(
+                            groups.len() as _, groups.as_ptr()),
+                        None if uid.is_some() && libc::getuid() == 0 =>
+                            libc::setgroups(0, ptr::null()),
+                        None => 0,
+                    };
+                    if result == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    if let Some(gid) = gid {
+                        if libc::setgid(gid) == -1 {
+                            return Err(io::Error::last_os_error());
+                        }
+                    }
+                    if let Some(uid) = uid {
+                        if libc::setuid(uid) == -1 {
+                            return Err(io::Error::last_os_error());
+                        }
+                    }
+                    Ok(())
+                });
+            }
+        }
+        // Changing credentials clears the parent death signal, so this must
+        // come last.
+        if self.die_with_parent {
+            let parent = unsafe { libc::getpid() };
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::prctl(libc::PR_SET_PDEATHSIG,
+                                   libc::SIGKILL as libc::c_ulong) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    // The parent may have exited between fork and prctl, in
+                    // which case the signal is never delivered.
+                    if libc::getppid() != parent {
+                        return Err(io::Error::from_raw_os_error(libc::ESRCH));
+                    }
+                    Ok(())
+                });
+            }
+        }
+    }
+
+    pub fn spawn(mut self) -> Result<ShellChild, ShellError> {
+        if let Some(result) = dry_run() {
+            eprintln!("[dry-run] {}", self.quoted_line());
+            return Ok(ShellChild::dry_run(DryRunChild::new(
+                        self.command_line(), result, self.success.clone())));
+        }
+        let mut command = self.command();
+        let stdout_tee = match self.stdout_tee.take() {
+            Some(target) => Some(tee_callback(
+                    target, Passthrough::Stdout, self.tee_prefix.clone())?),
+            None => None,
+        };
+        let stdout_callback = chain_callbacks(
+            stdout_tee, self.stdout_callback.take().map(line_output_callback));
+        let stderr_tee = match self.stderr_tee.take() {
+            Some(target) => Some(tee_callback(
+                    target, Passthrough::Stderr, self.tee_prefix.clone())?),
+            None => None,
+        };
+        let stderr_callback = chain_callbacks(
+            stderr_tee, self.stderr_callback.take().map(line_output_callback));
+        if self.pty.is_some() && (stdout_callback.is_some() ||
+                stderr_callback.is_some() || self.capture_output) {
+            return Err(ShellError::from(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Output of a pty cannot be passed to callbacks, tee or \
+                         capture_output(), read ShellChild::pty() instead")));
+        }
+        if stdout_callback.is_some() || self.capture_output {
+            command.stdout(Stdio::piped());
+        }
+        if stderr_callback.is_some() || self.capture_output {
+            command.stderr(Stdio::piped());
+        }
+        let pty = match self.pty {
+            Some((rows, cols)) => {
+                let (master, slave) = open_pty(rows, cols)?;
+                command.stdin(slave.try_clone()?);
+                command.stdout(slave.try_clone()?);
+                command.stderr(slave);
+                Some(master)
+            }
+            None => None
+        };
+        let line = self.command_line();
+        let switches_user = self.switches_user();
+        let xtrace = xtrace();
+        if let Some(ref xtrace) = xtrace {
+            xtrace.spawned(&self.quoted_line());
+        }
+        let journal = journal().map(|journal| (journal, self.journal_entry()));
+        // The command is dropped here, which closes the slave side of the pty
+        // in the current process.
+        let child = match ShellChild::new(line.clone(), command) {
+            Ok(child) => child,
+            Err(error) => {
+                let error = match error {
+                    ShellError::IoError(error) if switches_user &&
+                            error.raw_os_error() == Some(libc::EPERM) =>
+                        ShellError::Privilege(line, error),
+                    error => error,
+                };
+                if let Some((journal, mut entry)) = journal {
+                    entry.end = Some(SystemTime::now());
+                    entry.error = Some(error.to_string());
+                    journal.write(&entry);
+                }
+                return Err(error);
+            }
+        };
+        {
+            let mut lock = child.process().write().unwrap();
+            let core = lock.as_mut().ok_or(ShellError::NoSuchProcess)?;
+            core.pty = pty;
+            core.success = self.success.take();
+            core.collect_usage = self.collect_usage;
+            core.xtrace = xtrace;
+            core.journal = journal.map(|(journal, mut entry)| {
+                entry.pid = core.child.id();
+                (journal, entry)
+            });
+            if let Some(callback) = stdout_callback {
+                let stdout = core.child.stdout.take()
+                    .ok_or_else(stdout_not_piped)?;
+                core.readers.push(spawn_line_reader(stdout, callback));
+            }
+            if let Some(callback) = stderr_callback {
+                let stderr = core.child.stderr.take()
+                    .ok_or_else(stderr_not_piped)?;
+                core.readers.push(spawn_line_reader(stderr, callback));
+            }
+            if self.capture_output {
+                core.capture = Some(spawn_capture(
+                        core.child.stdout.take(), core.child.stderr.take()));
+            }
+        }
+        Ok(child)
+    }
+
+    /// Runs the command and returns its stdout. Fails without running the
+    /// command if stdout is passed to callbacks, tee or `capture_output()`.
+    pub fn stdout_utf8(self) -> Result<String, ShellError> {
+        self.check_stdout_available()?;
+        self.stdout(StdioSpec::Piped).spawn()?.stdout_utf8()
+    }
+
+    /// Runs the command and returns an iterator over lines of stdout.
+    pub fn lines(self) -> Result<ShellLines, ShellError> {
+        self.check_stdout_available()?;
+        self.stdout(StdioSpec::Piped).spawn()?.lines()
+    }
+
+    fn check_stdout_available(&self) -> Result<(), ShellError> {
+        if self.stdout_callback.is_some() || self.stdout_tee.is_some() ||
+                self.capture_output {
+            return Err(stdout_not_piped());
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ShellCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ShellCommand")
+            .field("line", &self.line)
+            .field("program", &self.program)
+            .field("args", &self.args)
+            .field("env", &self.env)
+            .field("env_clear", &self.env_clear)
+            .field("current_dir", &self.current_dir)
+            .field("stdin", &self.stdin)
+            .field("stdout", &self.stdout)
+            .field("stderr", &self.stderr)
+            .field("die_with_parent", &self.die_with_parent)
+            .field("rlimits", &self.rlimits)
+            .field("nice", &self.nice)
+            .field("umask", &self.umask)
+            .field("ioprio", &self.ioprio)
+            .field("uid", &self.uid)
+            .field("gid", &self.gid)
+            .field("groups", &self.groups)
+            .field("pty", &self.pty)
+            .field("stdout_tee", &self.stdout_tee)
+            .field("stderr_tee", &self.stderr_tee)
+            .field("tee_prefix", &self.tee_prefix)
+            .field("capture_output", &self.capture_output)
+            .field("collect_usage", &self.collect_usage)
+            .field("retry", &self.retry)
+            .field("hooks", &self.hooks.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Compares descriptions. Callbacks and predicates are equal only if they are
+/// shared by clones.
+impl PartialEq for ShellCommand {
+    fn eq(&self, other: &ShellCommand) -> bool {
+        self.line == other.line &&
+            self.program == other.program &&
+            self.args == other.args &&
+            self.env == other.env &&
+            self.env_clear == other.env_clear &&
+            self.current_dir == other.current_dir &&
+            self.stdin == other.stdin &&
+            self.stdout == other.stdout &&
+            self.stderr == other.stderr &&
+            self.die_with_parent == other.die_with_parent &&
+            self.rlimits == other.rlimits &&
+            self.nice == other.nice &&
+            self.umask == other.umask &&
+            self.ioprio == other.ioprio &&
+            self.uid == other.uid &&
+            self.gid == other.gid &&
+            self.groups == other.groups &&
+            self.user_name == other.user_name &&
+            self.pty == other.pty &&
+            same_arc(&self.stdout_callback, &other.stdout_callback) &&
+            same_arc(&self.stderr_callback, &other.stderr_callback) &&
+            self.stdout_tee == other.stdout_tee &&
+            self.stderr_tee == other.stderr_tee &&
+            self.tee_prefix == other.tee_prefix &&
+            self.capture_output == other.capture_output &&
+            self.collect_usage == other.collect_usage &&
+            same_arc(&self.success, &other.success) &&
+            self.retry == other.retry &&
+            self.hooks.len() == other.hooks.len() &&
+            self.hooks.iter().zip(&other.hooks)
+                .all(|(a, b)| Arc::ptr_eq(a, b))
     }
 }
 
@@ -54,6 +1008,153 @@ fn test_shell_command() {
 #[test]
 fn test_shell_command_output() {
     assert_eq!(&String::from_utf8_lossy(
-        &cmd!("echo Test").command.output().unwrap().stdout), "Test\n");
+        &cmd!("echo Test").command().output().unwrap().stdout), "Test\n");
     assert_eq!(cmd!("echo Test").stdout_utf8().unwrap(), "Test\n");
 }
+
+#[test]
+fn test_shell_command_limits() {
+    let output = cmd!("sh -c {}", "ulimit -n; umask; nice")
+        .rlimit(libc::RLIMIT_NOFILE, 64, 64)
+        .umask(0o077)
+        .nice(5)
+        .ioprio(IoPriority::Idle).unwrap()
+        .stdout_utf8().unwrap();
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], "64");
+    assert_eq!(lines[1], "0077");
+    assert!(lines[2].parse::<i32>().unwrap() >= 5);
+    assert!(cmd!("true").ioprio(IoPriority::BestEffort(7)).is_ok());
+    assert!(cmd!("true").ioprio(IoPriority::RealTime(8)).is_err());
+}
+
+#[test]
+fn test_shell_command_cpu_limit() {
+    let error = cmd!("sh -c {}", "while :; do :; done")
+        .rlimit(libc::RLIMIT_CPU, 1, 2)
+        .run().err().unwrap();
+    assert!(error.to_string().ends_with("(CPU time limit exceeded)"));
+}
+
+#[test]
+fn test_shell_command_user() {
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+    assert_eq!(cmd!("id -u").uid(uid).gid(gid).stdout_utf8().unwrap(),
+               format!("{}\n", uid));
+    if uid == 0 {
+        assert_eq!(cmd!("id -un").as_user("nobody").unwrap()
+                   .stdout_utf8().unwrap(), "nobody\n");
+    } else {
+        match cmd!("true").uid(0).run() {
+            Err(ShellError::Privilege(line, _)) =>
+                assert_eq!(line, "[0] true"),
+            _ => panic!("Should fail with ShellError::Privilege"),
+        }
+    }
+}
+
+#[test]
+fn test_shell_command_pty() {
+    use std::io::Read;
+    use std::io::Write;
+
+    assert!(cmd!("tty").pty(24, 80).stdout_utf8().unwrap()
+            .starts_with("/dev/pts/"));
+    assert_eq!(cmd!("stty size").pty(24, 80).stdout_utf8().unwrap(),
+               "24 80\r\n");
+
+    let child = cmd!("sh -c {}", "read line; stty size")
+        .pty(24, 80).spawn().unwrap();
+    let mut pty = child.pty().unwrap();
+    pty.resize(30, 100).unwrap();
+    pty.write_all(b"\n").unwrap();
+    let mut output = String::new();
+    pty.read_to_string(&mut output).unwrap();
+    assert!(output.contains("30 100"));
+    child.wait().unwrap();
+
+    let lines = cmd!("stty size").pty(24, 80).lines().unwrap()
+        .collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(lines, vec!["24 80\r"]);
+    assert!(cmd!("true").pty(24, 80).on_stdout_line(|_| ()).spawn().is_err());
+    let child = cmd!("true").spawn().unwrap();
+    assert!(child.lines().is_err());
+}
+
+#[test]
+fn test_shell_command_ok_codes() {
+    use result::ShellResultExt;
+
+    let result = cmd!("grep no-such-line /dev/null").ok_codes(&[0, 1]).run();
+    assert!(result.is_ok());
+    assert_eq!(result.code(), 1);
+    assert!(cmd!("grep -qs . /no/such/file").ok_codes(&[0, 1]).run().is_err());
+
+    let result = cmd!("sh -c {}", "exit 3")
+        .success_if(|status| status.code() == Some(3)).run();
+    assert_eq!(result.status().unwrap().code(), Some(3));
+    assert!(cmd!("true").success_if(|status| !status.success()).run()
+            .is_err());
+}
+
+#[test]
+fn test_shell_command_clone() {
+    let command = cmd!("sh -c {}", "echo $A $0")
+        .arg("B")
+        .env("A", "a")
+        .current_dir("/");
+    assert_eq!(command.clone(), command);
+    assert_ne!(command.clone().arg("C"), command);
+    assert_eq!(command.clone().stdout_utf8().unwrap(), "a B\n");
+    assert_eq!(command.stdout_utf8().unwrap(), "a B\n");
+}
+
+#[test]
+fn test_shell_command_xargs_line() {
+    let commands = cmd!("echo").xargs_commands(vec!["a"; 3]);
+    assert_eq!(commands.len(), 1);
+    assert_eq!(commands[0].command_line(), "echo <3 args>");
+    assert_eq!(commands[0].args, vec!["a", "a", "a"]);
+}
+
+#[test]
+fn test_shell_command_line() {
+    let command = cmd!("echo {}", "a b").arg("c d").env("E", "e f");
+    assert_eq!(command.command_line(), "echo 'a b' 'c d'");
+    assert_eq!(command.quoted_line(), "E='e f' echo 'a b' 'c d'");
+}
+
+#[test]
+fn test_shell_command_quoted_line_context() {
+    use environment::cd;
+    use environment::export;
+    use environment::unset;
+
+    export("X", "1");
+    unset("Y");
+    cd("/").unwrap();
+    let command = cmd!("rm -rf build").current_dir("srv").uid(65534);
+    assert_eq!(command.quoted_line(),
+               "(cd /srv && [65534] env -u Y X=1 rm -rf build)");
+    let command = cmd!("true").env_clear().env("Z", "a b");
+    assert_eq!(command.quoted_line(), "(cd / && env -i Z='a b' true)");
+}
+
+#[test]
+fn test_shell_command_configure() {
+    use std::fs;
+    use std::fs::File;
+
+    let path = env::temp_dir().join(
+        format!("shell_command_configure_{}", std::process::id()));
+    let file = File::create(&path).unwrap();
+    let command = cmd!("echo configured").configure(move |command| {
+        command.stdout(file.try_clone().unwrap());
+    });
+    command.clone().run().unwrap();
+    command.run().unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(),
+               "configured\nconfigured\n");
+    fs::remove_file(&path).unwrap();
+}
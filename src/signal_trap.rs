@@ -0,0 +1,300 @@
+// Copyright 2017 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use errno::Errno;
+use errno::errno;
+use libc::c_int;
+use libc::pid_t;
+use libc::sigset_t;
+use libc;
+use local_shell::LocalShell;
+use local_shell::wait_processes;
+use process_manager::PROCESS_MANAGER;
+use result::ShellError;
+use result::check_errno;
+use shell_child::ShellChildCore;
+use std::collections::BTreeSet;
+use std::collections::HashSet;
+use std::mem;
+use std::process;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use subreaper;
+
+lazy_static! {
+    static ref TRAPPED_SIGNALS: Mutex<Vec<c_int>> = Mutex::new(Vec::new());
+}
+
+/// Returns signals blocked by `SignalTrap::install()`, which child processes
+/// unblock before exec as they would inherit the mask otherwise.
+pub fn trapped_signals() -> Vec<c_int> {
+    TRAPPED_SIGNALS.lock().unwrap().clone()
+}
+
+/// Function run after children exit and before the process exits. It receives
+/// the trapped signal.
+pub type CleanupHook = Box<dyn FnOnce(c_int) + Send>;
+
+/// Builder of a thread handling signals sent to the current process.
+///
+/// When one of the signals is received, the thread forwards it to every
+/// `LocalShell`, waits for child processes, runs cleanup hooks and exits the
+/// current process with `128 + signal`. Receiving a trapped signal again while
+/// waiting sends SIGKILL to the remaining processes.
+pub struct SignalTrap {
+    signals: Vec<c_int>,
+    forward: bool,
+    shutdown_timeout: Option<Duration>,
+    hooks: Vec<CleanupHook>,
+}
+
+impl Default for SignalTrap {
+    fn default() -> SignalTrap {
+        SignalTrap::new()
+    }
+}
+
+impl SignalTrap {
+    /// Traps SIGINT and SIGTERM, forwards them to children and waits for the
+    /// children without timeout.
+    pub fn new() -> SignalTrap {
+        SignalTrap {
+            signals: vec![libc::SIGINT, libc::SIGTERM],
+            forward: true,
+            shutdown_timeout: None,
+            hooks: Vec::new(),
+        }
+    }
+
+    /// Replaces the trapped signals.
+    pub fn signals(mut self, signals: &[c_int]) -> SignalTrap {
+        self.signals = signals.to_vec();
+        self
+    }
+
+    /// Adds a trapped signal, e.g. SIGHUP.
+    pub fn signal(mut self, signal: c_int) -> SignalTrap {
+        self.signals.push(signal);
+        self
+    }
+
+    /// Sends the received signal to processes of every `LocalShell` before
+    /// waiting for them. Enabled by default, as children in another process
+    /// group do not receive Ctrl-C from the terminal. Signals sent by the
+    /// terminal are not forwarded to children in the current process group,
+    /// which have received them already.
+    pub fn forward(mut self, forward: bool) -> SignalTrap {
+        self.forward = forward;
+        self
+    }
+
+    /// Sends SIGKILL to processes still running `timeout` after the signal.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> SignalTrap {
+        self.shutdown_timeout = Some(timeout);
+        self
+    }
+
+    /// Adds a function run before exit. Hooks run in the order of addition.
+    pub fn on_exit<F>(mut self, hook: F) -> SignalTrap
+            where F: FnOnce(c_int) + Send + 'static {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Starts the thread handling signals.
+    ///
+    /// It must be invoked before any thread is launched, because it internally
+    /// uses pthread_sigmask.
+    pub fn install(self) -> Result<(), ShellError> {
+        let sigset = unsafe {
+            let mut sigset = mem::zeroed::<sigset_t>();
+            check_errno("sigemptyset",
+                        libc::sigemptyset(&mut sigset as *mut sigset_t))?;
+            for &signal in &self.signals {
+                check_errno("sigaddset", libc::sigaddset(
+                        &mut sigset as *mut sigset_t, signal))?;
+            }
+            let result = libc::pthread_sigmask(
+                libc::SIG_BLOCK, &sigset as *const sigset_t, ptr::null_mut());
+            if result != 0 {
+                return Err(ShellError::Errno("pthread_sigmask", Errno(result)));
+            }
+            sigset
+        };
+        TRAPPED_SIGNALS.lock().unwrap().extend(&self.signals);
+        thread::spawn(move || {
+            info!("Start waitinig signal");
+            let (signal, from_kernel) = match wait_signal(&sigset) {
+                Ok(result) => result,
+                Err(error) => {
+                    eprintln!("sigwait failed {}", error);
+                    return;
+                }
+            };
+            info!("Signal {} is received", signal);
+            self.shutdown(signal, from_kernel, sigset);
+        });
+        Ok(())
+    }
+
+    fn shutdown(self, signal: c_int, from_kernel: bool, sigset: sigset_t) {
+        let shells = PROCESS_MANAGER.lock().unwrap().drain_local_shells();
+        // Child shells are also registered, and signals recurse into them.
+        let roots = root_shells(&shells);
+        if self.forward {
+            info!("Forward signal {} to {} shells", signal, roots.len());
+            // A signal from the terminal, e.g. Ctrl-C, has already reached
+            // every process in the foreground process group, which includes
+            // the current process.
+            let group = unsafe { libc::getpgrp() };
+            let forwarded = |child: &ShellChildCore| !from_kernel ||
+                unsafe { libc::getpgid(child.child.id() as pid_t) } != group;
+            for shell in &roots {
+                shell.lock().unwrap().signal_if(signal, &forwarded);
+            }
+        }
+        info!("Wait for {} child processes exiting", running_pids(&roots).len());
+        let escalation_roots = roots.clone();
+        thread::spawn(move || {
+            if let Ok((signal, _)) = wait_signal(&sigset) {
+                info!("Signal {} is received again, kill processes", signal);
+                kill_all(&escalation_roots);
+            }
+        });
+        let (sender, receiver) = mpsc::channel();
+        let waiting_roots = roots.clone();
+        thread::spawn(move || {
+            // Shells are not locked while processes run, so that the pids can
+            // be collected again when killing them.
+            while running_pids(&waiting_roots).into_iter()
+                    .any(is_running_child) {
+                thread::sleep(Duration::from_millis(10));
+            }
+            for shell in &shells {
+                wait_processes(shell);
+            }
+            if subreaper::is_subreaper() {
+                info!("Wait for descendant processes exiting");
+                if let Err(error) = subreaper::wait_descendants() {
+                    error!("Failed to wait descendants {:?}", error);
+                }
+            }
+            // The receiver is gone if the trap thread exits first.
+            let _ = sender.send(());
+        });
+        if let Some(timeout) = self.shutdown_timeout {
+            if receiver.recv_timeout(timeout).is_err() {
+                info!("Kill processes remaining after {:?}", timeout);
+                kill_all(&roots);
+            }
+        }
+        let _ = receiver.recv();
+        for hook in self.hooks {
+            hook(signal);
+        }
+        process::exit(128 + signal);
+    }
+}
+
+/// Waits for a signal in `sigset`. Returns the signal and true if it is sent by
+/// the kernel, e.g. by the terminal, rather than by a process.
+fn wait_signal(sigset: &sigset_t) -> Result<(c_int, bool), Errno> {
+    loop {
+        let mut info = unsafe { mem::zeroed::<libc::siginfo_t>() };
+        let signal = unsafe {
+            libc::sigwaitinfo(sigset as *const sigset_t,
+                              &mut info as *mut libc::siginfo_t)
+        };
+        if signal != -1 {
+            return Ok((signal, info.si_code == libc::SI_KERNEL));
+        }
+        if errno().0 != libc::EINTR {
+            return Err(errno());
+        }
+    }
+}
+
+/// Returns the shells in `shells` which are not descendants of another one, so
+/// that signaling them recursively reaches each process once.
+fn root_shells(shells: &[Arc<Mutex<LocalShell>>])
+        -> Vec<Arc<Mutex<LocalShell>>> {
+    let mut descendants = HashSet::new();
+    let mut pending = shells.to_vec();
+    while let Some(shell) = pending.pop() {
+        for child in shell.lock().unwrap().children() {
+            if descendants.insert(Arc::as_ptr(&child)) {
+                pending.push(child);
+            }
+        }
+    }
+    shells.iter()
+        .filter(|shell| !descendants.contains(&Arc::as_ptr(shell)))
+        .cloned()
+        .collect()
+}
+
+/// Returns pids of processes running on `shells` and their descendants.
+fn running_pids(shells: &[Arc<Mutex<LocalShell>>]) -> BTreeSet<libc::pid_t> {
+    shells.iter()
+        .flat_map(|shell| shell.lock().unwrap().running_processes())
+        .map(|process| process.pid as libc::pid_t)
+        .collect()
+}
+
+/// Returns true if `pid` is a child of the current process which has not
+/// exited yet, so the pid cannot refer to another process.
+fn is_running_child(pid: libc::pid_t) -> bool {
+    unsafe {
+        let mut info = mem::zeroed::<libc::siginfo_t>();
+        let result = libc::waitid(
+            libc::P_PID, pid as libc::id_t, &mut info as *mut libc::siginfo_t,
+            libc::WEXITED | libc::WNOHANG | libc::WNOWAIT);
+        result == 0 && info.si_pid() == 0
+    }
+}
+
+/// Sends SIGKILL to processes running on `roots` and on shells registered
+/// after the signal, e.g. by threads started since then, and to every
+/// descendant if the current process is a subreaper.
+///
+/// Processes are signaled through their shells, so that a process reaped in
+/// the meantime is not confused with another one reusing its pid.
+fn kill_all(roots: &[Arc<Mutex<LocalShell>>]) {
+    let mut shells = roots.to_vec();
+    shells.extend(PROCESS_MANAGER.lock().unwrap().drain_local_shells());
+    for shell in &root_shells(&shells) {
+        shell.lock().unwrap().signal(libc::SIGKILL);
+    }
+    if subreaper::is_subreaper() {
+        subreaper::kill_descendants(libc::SIGKILL);
+    }
+}
+
+#[test]
+fn test_root_shells() {
+    use local_shell::current_shell;
+    use local_shell::new_child_shell;
+    let parent = new_child_shell(&current_shell());
+    let child = new_child_shell(&parent);
+    let grandchild = new_child_shell(&child);
+    let other = new_child_shell(&current_shell());
+    let roots = root_shells(&[grandchild, other.clone(), parent.clone()]);
+    assert_eq!(roots.len(), 2);
+    assert!(Arc::ptr_eq(&roots[0], &other));
+    assert!(Arc::ptr_eq(&roots[1], &parent));
+}
@@ -0,0 +1,133 @@
+// Copyright 2017 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use result::ShellError;
+use shell_child::ShellChild;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::process::ChildStdout;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::thread;
+
+/// Function receiving a line of output without the trailing newline.
+///
+/// It is shared so that a command can be spawned multiple times.
+pub type LineCallback = Arc<Mutex<dyn FnMut(&str) + Send + 'static>>;
+
+/// Function receiving the raw bytes of a line of output, including the
+/// trailing newline if the line has one.
+pub type OutputCallback = Arc<Mutex<dyn FnMut(&[u8]) + Send + 'static>>;
+
+/// Adapts `callback` to receive lines from `spawn_line_reader()`.
+pub fn line_output_callback(callback: LineCallback) -> OutputCallback {
+    Arc::new(Mutex::new(move |line: &[u8]| {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        (*callback.lock().unwrap())(&String::from_utf8_lossy(line));
+    }))
+}
+
+/// Reads a line from `reader` and returns it without the trailing newline.
+/// Returns `None` at the end of the stream.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>, ShellError> {
+    let mut buffer = Vec::new();
+    if reader.read_until(b'\n', &mut buffer)? == 0 {
+        return Ok(None);
+    }
+    if buffer.last() == Some(&b'\n') {
+        buffer.pop();
+    }
+    Ok(Some(String::from_utf8_lossy(&buffer).into_owned()))
+}
+
+/// Starts a thread passing each line read from `reader` to `callback`.
+pub fn spawn_line_reader<R>(reader: R, callback: OutputCallback)
+        -> JoinHandle<()> where R: Read + Send + 'static {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        let mut buffer = Vec::new();
+        loop {
+            buffer.clear();
+            match reader.read_until(b'\n', &mut buffer) {
+                Ok(0) => break,
+                Ok(_) => (*callback.lock().unwrap())(&buffer),
+                Err(error) => {
+                    error!("Failed to read output {:?}", error);
+                    break;
+                }
+            }
+        }
+    })
+}
+
+/// Iterator over lines of stdout returned by `ShellChild::lines()`.
+///
+/// Once stdout is closed, the iterator waits for the process and yields an
+/// error as the last item if the process failed. Dropping the iterator closes
+/// stdout and waits for the process.
+pub struct ShellLines {
+    child: Option<ShellChild>,
+    stdout: Option<Box<dyn BufRead + Send>>,
+}
+
+impl ShellLines {
+    pub fn new(child: ShellChild, stdout: ChildStdout) -> ShellLines {
+        ShellLines::from_reader(child, BufReader::new(stdout))
+    }
+
+    /// Reads lines from `stdout` instead of the stdout of `child`.
+    pub fn from_reader<R>(child: ShellChild, stdout: R) -> ShellLines
+            where R: BufRead + Send + 'static {
+        ShellLines {
+            child: Some(child),
+            stdout: Some(Box::new(stdout)),
+        }
+    }
+}
+
+impl Iterator for ShellLines {
+    type Item = Result<String, ShellError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = match self.stdout.as_mut() {
+            Some(stdout) => read_line(stdout),
+            None => return None,
+        };
+        match line {
+            Ok(Some(line)) => Some(Ok(line)),
+            Ok(None) => {
+                self.stdout = None;
+                self.child.take().and_then(|child| child.wait().err())
+                    .map(Err)
+            }
+            Err(error) => {
+                self.stdout = None;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+impl Drop for ShellLines {
+    fn drop(&mut self) {
+        self.stdout = None;
+        if let Some(child) = self.child.take() {
+            if let Err(error) = child.wait() {
+                debug!("Process exits with error {:?}", error);
+            }
+        }
+    }
+}
@@ -0,0 +1,225 @@
+// Copyright 2017 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use errno::errno;
+use libc::c_int;
+use libc::pid_t;
+use libc;
+use result::ShellError;
+use result::check_errno;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::mem;
+use std::process::Child;
+use std::process::Command;
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+static SUBREAPER: AtomicBool = AtomicBool::new(false);
+
+/// Interval between scans for orphaned processes.
+const REAP_INTERVAL_MS: u64 = 100;
+
+lazy_static! {
+    /// Held for reading while spawning a child and registering its pid, and
+    /// for writing while reaping, so that the reaper never sees a child
+    /// before its registration.
+    static ref SPAWN_LOCK: RwLock<()> = RwLock::new(());
+    /// Pids of children spawned by `ShellChild`, which reaps them itself.
+    static ref CHILD_PIDS: Mutex<HashSet<pid_t>> = Mutex::new(HashSet::new());
+}
+
+/// Spawns `command` and registers the child, so that the reaper thread leaves
+/// it to its owner.
+pub fn spawn_child(command: &mut Command) -> io::Result<Child> {
+    let _lock = SPAWN_LOCK.read().unwrap();
+    let child = command.spawn()?;
+    CHILD_PIDS.lock().unwrap().insert(child.id() as pid_t);
+    Ok(child)
+}
+
+/// Reaps a child spawned by `spawn_child()` by `reap`, which must not block,
+/// and unregisters it. The pid cannot be reused by a child registered in
+/// between.
+pub fn reap_child<F, T>(pid: pid_t, reap: F) -> Result<T, ShellError>
+        where F: FnOnce() -> Result<T, ShellError> {
+    let mut child_pids = CHILD_PIDS.lock().unwrap();
+    let result = reap();
+    if result.is_ok() {
+        child_pids.remove(&pid);
+    }
+    result
+}
+
+/// Unregisters a child spawned by `spawn_child()` which is dropped without
+/// wait, so that the reaper thread reaps it.
+pub fn unregister_child(pid: pid_t) {
+    CHILD_PIDS.lock().unwrap().remove(&pid);
+}
+
+/// Returns pid, parent pid and state of every process listed in /proc.
+fn processes() -> Vec<(pid_t, pid_t, char)> {
+    let mut result = Vec::new();
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(error) => {
+            error!("Failed to read /proc {:?}", error);
+            return result;
+        }
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let pid = match entry.file_name().to_str()
+                .and_then(|name| name.parse::<pid_t>().ok()) {
+            Some(pid) => pid,
+            None => continue
+        };
+        // The process may have exited since read_dir.
+        let stat = match fs::read_to_string(entry.path().join("stat")) {
+            Ok(stat) => stat,
+            Err(_) => continue
+        };
+        // The command name is enclosed by parentheses and may contain spaces.
+        let rest = match stat.rfind(')') {
+            Some(index) => &stat[index + 1..],
+            None => continue
+        };
+        let mut fields = rest.split_whitespace();
+        let state = fields.next().and_then(|field| field.chars().next());
+        let ppid = fields.next().and_then(|field| field.parse().ok());
+        if let (Some(state), Some(ppid)) = (state, ppid) {
+            result.push((pid, ppid, state));
+        }
+    }
+    result
+}
+
+/// Returns true if a child of the current process has exited and is not
+/// reaped yet.
+fn has_exited_child() -> bool {
+    unsafe {
+        let mut info = mem::zeroed::<libc::siginfo_t>();
+        libc::waitid(libc::P_ALL, 0, &mut info as *mut libc::siginfo_t,
+                     libc::WEXITED | libc::WNOHANG | libc::WNOWAIT) == 0 &&
+            info.si_pid() != 0
+    }
+}
+
+/// Reaps exited children which are not registered by `spawn_child()`, i.e.
+/// orphans re-parented to the current process.
+fn reap_orphans() {
+    if !has_exited_child() {
+        return;
+    }
+    let _lock = SPAWN_LOCK.write().unwrap();
+    let current = unsafe { libc::getpid() };
+    let child_pids = CHILD_PIDS.lock().unwrap().clone();
+    for (pid, ppid, state) in processes() {
+        if ppid == current && state == 'Z' && !child_pids.contains(&pid) {
+            debug!("Reaping orphaned process {}", pid);
+            unsafe {
+                libc::waitpid(pid, ptr::null_mut(), libc::WNOHANG);
+            }
+        }
+    }
+}
+
+/// Marks the current process as a child subreaper.
+///
+/// Descendants orphaned by their parents are re-parented to the current
+/// process instead of init, and a background thread reaps them once they
+/// exit. The thread reaps every exited child except processes spawned by
+/// `ShellCommand`, which are waited by their owners, so children spawned
+/// directly by `std::process::Command` may be reaped before they are waited.
+/// Linux only.
+pub fn become_subreaper() -> Result<(), ShellError> {
+    if SUBREAPER.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    unsafe {
+        check_errno("prctl", libc::prctl(libc::PR_SET_CHILD_SUBREAPER,
+                                         1 as libc::c_ulong))?;
+    }
+    if !SUBREAPER.swap(true, Ordering::SeqCst) {
+        thread::spawn(|| {
+            info!("Start reaping orphaned processes");
+            loop {
+                reap_orphans();
+                thread::sleep(Duration::from_millis(REAP_INTERVAL_MS));
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Returns true if `become_subreaper()` has been called.
+pub fn is_subreaper() -> bool {
+    SUBREAPER.load(Ordering::SeqCst)
+}
+
+/// Returns pids of all descendants of the current process.
+pub fn descendants() -> Vec<pid_t> {
+    let mut children = HashMap::<pid_t, Vec<pid_t>>::new();
+    for (pid, ppid, _) in processes() {
+        children.entry(ppid).or_default().push(pid);
+    }
+    let mut result = Vec::new();
+    let mut queue = vec![unsafe { libc::getpid() }];
+    while let Some(pid) = queue.pop() {
+        if let Some(pids) = children.get(&pid) {
+            result.extend(pids);
+            queue.extend(pids);
+        }
+    }
+    result
+}
+
+/// Sends a signal to every descendant of the current process, including ones
+/// not spawned by `ShellCommand`.
+pub fn kill_descendants(signal: c_int) {
+    for pid in descendants() {
+        info!("Sending signal {} to descendant {}", signal, pid);
+        unsafe {
+            if libc::kill(pid, signal) == -1 && errno().0 != libc::ESRCH {
+                error!("Failed to send a signal {:?}", errno());
+            }
+        }
+    }
+}
+
+/// Waits until every child of the current process exits.
+///
+/// After `become_subreaper()`, orphaned grandchildren are re-parented to the
+/// current process, so the function returns once every descendant exits. Exit
+/// statuses are consumed, so `ShellChild::wait()` running concurrently may
+/// fail. It is intended for the shutdown path.
+pub fn wait_descendants() -> Result<(), ShellError> {
+    loop {
+        let pid = unsafe { libc::waitpid(-1, ptr::null_mut(), 0) };
+        if pid != -1 {
+            continue;
+        }
+        match errno().0 {
+            libc::ECHILD => return Ok(()),
+            libc::EINTR => continue,
+            _ => return Err(ShellError::Errno("waitpid", errno()))
+        }
+    }
+}
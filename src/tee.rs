@@ -0,0 +1,177 @@
+// Copyright 2017 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use result::ShellError;
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use stream::OutputCallback;
+
+/// In-memory buffer which output can be teed to.
+///
+/// Clones share the same buffer, so a clone can be passed to
+/// `ShellCommand::tee_stdout()` and the original read after the command
+/// finishes.
+#[derive(Clone, Debug, Default)]
+pub struct TeeBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl TeeBuffer {
+    pub fn new() -> TeeBuffer {
+        TeeBuffer::default()
+    }
+
+    /// Returns a copy of the bytes written so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Returns the bytes written so far as a string.
+    pub fn to_string_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl Write for TeeBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Destination which output is copied to in addition to the terminal.
+#[derive(Clone)]
+pub enum TeeTarget {
+    /// File created (or truncated) when the command is spawned.
+    File(PathBuf),
+    /// Writer shared by every spawn of the command.
+    Writer(Arc<Mutex<dyn Write + Send>>),
+}
+
+impl fmt::Debug for TeeTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TeeTarget::File(ref path) =>
+                f.debug_tuple("File").field(path).finish(),
+            TeeTarget::Writer(_) => f.write_str("Writer"),
+        }
+    }
+}
+
+/// Writers are equal only if they are shared.
+impl PartialEq for TeeTarget {
+    fn eq(&self, other: &TeeTarget) -> bool {
+        match (self, other) {
+            (TeeTarget::File(a), TeeTarget::File(b)) => a == b,
+            (TeeTarget::Writer(a), TeeTarget::Writer(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl TeeTarget {
+    fn open(self) -> Result<Arc<Mutex<dyn Write + Send>>, ShellError> {
+        match self {
+            TeeTarget::File(path) =>
+                Ok(Arc::new(Mutex::new(File::create(path)?))),
+            TeeTarget::Writer(writer) => Ok(writer),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for TeeTarget {
+    fn from(path: &'a str) -> TeeTarget {
+        TeeTarget::File(PathBuf::from(path))
+    }
+}
+
+impl<'a> From<&'a Path> for TeeTarget {
+    fn from(path: &'a Path) -> TeeTarget {
+        TeeTarget::File(path.to_path_buf())
+    }
+}
+
+impl From<PathBuf> for TeeTarget {
+    fn from(path: PathBuf) -> TeeTarget {
+        TeeTarget::File(path)
+    }
+}
+
+impl From<File> for TeeTarget {
+    fn from(file: File) -> TeeTarget {
+        TeeTarget::Writer(Arc::new(Mutex::new(file)))
+    }
+}
+
+impl From<TeeBuffer> for TeeTarget {
+    fn from(buffer: TeeBuffer) -> TeeTarget {
+        TeeTarget::Writer(Arc::new(Mutex::new(buffer)))
+    }
+}
+
+/// Output stream of the current process which teed output is passed to.
+#[derive(Clone, Copy)]
+pub enum Passthrough {
+    Stdout,
+    Stderr,
+}
+
+/// Returns a callback writing each line with `prefix` to both `passthrough`
+/// and `target`. Lines are written as read, without decoding or adding a
+/// newline.
+pub fn tee_callback(target: TeeTarget, passthrough: Passthrough,
+                    prefix: Option<String>)
+        -> Result<OutputCallback, ShellError> {
+    let writer = target.open()?;
+    let prefix = prefix.unwrap_or_default();
+    Ok(Arc::new(Mutex::new(move |line: &[u8]| {
+        // Write a whole line at once so that lines from other threads do not
+        // interleave.
+        let mut buffer = Vec::with_capacity(prefix.len() + line.len());
+        buffer.extend_from_slice(prefix.as_bytes());
+        buffer.extend_from_slice(line);
+        let result = match passthrough {
+            Passthrough::Stdout => io::stdout().write_all(&buffer),
+            Passthrough::Stderr => io::stderr().write_all(&buffer),
+        };
+        let result = result.and_then(
+            |_| writer.lock().unwrap().write_all(&buffer));
+        if let Err(error) = result {
+            error!("Failed to tee output {:?}", error);
+        }
+    })))
+}
+
+/// Combines two callbacks into one calling both of them.
+pub fn chain_callbacks(first: Option<OutputCallback>,
+                       second: Option<OutputCallback>)
+        -> Option<OutputCallback> {
+    match (first, second) {
+        (Some(first), Some(second)) =>
+            Some(Arc::new(Mutex::new(move |line: &[u8]| {
+                (*first.lock().unwrap())(line);
+                (*second.lock().unwrap())(line);
+            }))),
+        (first, None) => first,
+        (None, second) => second,
+    }
+}
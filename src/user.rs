@@ -0,0 +1,92 @@
+// Copyright 2017 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use errno::Errno;
+use libc::c_char;
+use libc::c_int;
+use libc::gid_t;
+use libc::uid_t;
+use libc;
+use result::ShellError;
+use std::ffi::CString;
+use std::mem;
+use std::ptr;
+
+/// Account information of a user obtained from the password database.
+pub struct User {
+    pub uid: uid_t,
+    pub gid: gid_t,
+    pub groups: Vec<gid_t>,
+}
+
+/// Looks up `name` by getpwnam_r and obtains its supplementary groups by
+/// getgrouplist.
+pub fn lookup_user(name: &str) -> Result<User, ShellError> {
+    let c_name = CString::new(name)
+        .map_err(|_| ShellError::NoSuchUser(String::from(name)))?;
+    let mut buffer = vec![0 as c_char; 1024];
+    let (uid, gid) = loop {
+        let mut passwd = unsafe { mem::zeroed::<libc::passwd>() };
+        let mut result = ptr::null_mut::<libc::passwd>();
+        let error = unsafe {
+            libc::getpwnam_r(c_name.as_ptr(), &mut passwd,
+                             buffer.as_mut_ptr(), buffer.len(), &mut result)
+        };
+        if error == libc::ERANGE {
+            let size = buffer.len() * 2;
+            buffer.resize(size, 0);
+            continue;
+        }
+        if error != 0 {
+            return Err(ShellError::Errno("getpwnam_r", Errno(error)));
+        }
+        if result.is_null() {
+            return Err(ShellError::NoSuchUser(String::from(name)));
+        }
+        break (passwd.pw_uid, passwd.pw_gid);
+    };
+
+    let mut groups = vec![0 as gid_t; 16];
+    loop {
+        let mut size = groups.len() as c_int;
+        let result = unsafe {
+            libc::getgrouplist(c_name.as_ptr(), gid, groups.as_mut_ptr(),
+                               &mut size)
+        };
+        // getgrouplist returns -1 and the required size if the buffer is too
+        // small.
+        if result == -1 {
+            let size = (size as usize).max(groups.len() * 2);
+            groups.resize(size, 0);
+            continue;
+        }
+        groups.truncate(size as usize);
+        break;
+    }
+
+    Ok(User {
+        uid,
+        gid,
+        groups,
+    })
+}
+
+#[test]
+fn test_lookup_user() {
+    let user = lookup_user("root").unwrap();
+    assert_eq!(user.uid, 0);
+    assert_eq!(user.gid, 0);
+    assert!(user.groups.contains(&0));
+    assert!(lookup_user("no-such-user-for-shell-test").is_err());
+}
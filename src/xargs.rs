@@ -0,0 +1,90 @@
+// Copyright 2017 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use libc;
+use result::ShellResult;
+use result::ok;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::mem;
+
+/// Bytes kept free in addition to the arguments and the environment, same as
+/// POSIX xargs.
+const HEADROOM: usize = 2048;
+
+/// Returns the bytes a string passed to exec takes from `ARG_MAX`, including
+/// the terminating NUL and the pointer to it.
+pub fn exec_size(string: &OsStr) -> usize {
+    string.len() + 1 + mem::size_of::<*const libc::c_char>()
+}
+
+/// Returns bytes available for arguments and environment variables of exec.
+fn arg_max() -> usize {
+    let result = unsafe { libc::sysconf(libc::_SC_ARG_MAX) };
+    if result <= 0 {
+        // Minimum value required by POSIX.
+        return 4096;
+    }
+    result as usize
+}
+
+/// Splits `args` into batches which fit in `ARG_MAX` after `used` bytes taken
+/// by the command itself and its environment.
+///
+/// Each batch contains at least one argument even if it does not fit.
+pub fn split_args<I>(args: I, used: usize) -> Vec<Vec<OsString>>
+        where I: IntoIterator<Item = OsString> {
+    let available = arg_max().saturating_sub(used + HEADROOM);
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    let mut size = 0;
+    for arg in args {
+        let arg_size = exec_size(&arg);
+        if !batch.is_empty() && size + arg_size > available {
+            batches.push(mem::take(&mut batch));
+            size = 0;
+        }
+        size += arg_size;
+        batch.push(arg);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+/// Returns the first failure of `results`, or success if all succeeded.
+pub fn aggregate<I>(results: I) -> ShellResult
+        where I: IntoIterator<Item = ShellResult> {
+    let mut last = ok();
+    for result in results {
+        match result {
+            Ok(exit) => last = Ok(exit),
+            Err(error) => return Err(error),
+        }
+    }
+    last
+}
+
+#[test]
+fn test_split_args() {
+    let available = arg_max() - HEADROOM;
+    let length = available / 3 - exec_size(OsStr::new(""));
+    let batches = split_args(vec![OsString::from("a".repeat(length)); 7], 0);
+    assert_eq!(batches.iter().map(|b| b.len()).collect::<Vec<_>>(),
+               vec![3, 3, 1]);
+    // An argument larger than the limit still gets its own batch.
+    let batches = split_args(vec![OsString::from("b"); 2], arg_max());
+    assert_eq!(batches.len(), 2);
+}
@@ -0,0 +1,76 @@
+// Copyright 2017 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use local_shell::current_shell;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+/// Where traced commands are printed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceOutput {
+    Stderr,
+    /// `info!` log messages with the given target.
+    Log(String),
+}
+
+/// Settings of `set -x` style tracing enabled by `set_xtrace()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XTrace {
+    /// Printed before each message.
+    pub prefix: String,
+    pub output: TraceOutput,
+}
+
+impl Default for XTrace {
+    /// Prints to stderr with `+ ` prefix like `set -x`.
+    fn default() -> XTrace {
+        XTrace {
+            prefix: String::from("+ "),
+            output: TraceOutput::Stderr,
+        }
+    }
+}
+
+impl XTrace {
+    fn print(&self, message: &str) {
+        match self.output {
+            TraceOutput::Stderr => eprintln!("{}{}", self.prefix, message),
+            TraceOutput::Log(ref target) =>
+                info!(target: target, "{}{}", self.prefix, message),
+        }
+    }
+
+    /// Traces a command line before it is spawned.
+    pub fn spawned(&self, line: &str) {
+        self.print(line);
+    }
+
+    /// Traces the exit status of a command after it is waited.
+    pub fn exited(&self, line: &str, status: &ExitStatus, duration: Duration) {
+        self.print(&format!("{} exited with {} in {:.3}s", line, status,
+                            duration.as_secs_f64()));
+    }
+}
+
+/// Enables tracing of commands run on the current `LocalShell`, or disables
+/// it if `xtrace` is `None`. Threads started by `shell::spawn()` inherit the
+/// setting.
+pub fn set_xtrace(xtrace: Option<XTrace>) {
+    current_shell().lock().unwrap().set_xtrace(xtrace);
+}
+
+/// Returns the tracing settings of the current `LocalShell`.
+pub fn xtrace() -> Option<XTrace> {
+    current_shell().lock().unwrap().xtrace()
+}
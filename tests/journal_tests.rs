@@ -0,0 +1,48 @@
+// Copyright 2017 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#[macro_use] extern crate shell;
+
+use shell::Journal;
+use shell::TeeBuffer;
+
+#[test]
+fn test_journal() {
+    let buffer = TeeBuffer::new();
+    shell::set_journal(Some(Journal::new(buffer.clone())));
+    assert!(cmd!("sh -c {}", "exit 3").env("A", "a b").current_dir("/").run()
+            .is_err());
+    cmd!("true").run().unwrap();
+    assert!(cmd!("/no/such/command").run().is_err());
+    drop(cmd!("true").spawn().unwrap());
+    shell::set_journal(None);
+    assert!(cmd!("false").run().is_err());
+
+    let journal = buffer.to_string_lossy();
+    let lines = journal.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].starts_with(
+            "{\"argv\":[\"sh\",\"-c\",\"exit 3\"],\"env\":{\"A\":\"a b\"},\
+             \"env_clear\":false,\"cwd\":\"/\",\"pid\":"));
+    assert!(lines[0].contains(",\"code\":3,\"signal\":null,\"usage\":{"));
+    assert!(lines[1].starts_with("{\"argv\":[\"true\"],"));
+    assert!(lines[1].contains(",\"code\":0,"));
+    assert!(lines[1].ends_with(",\"error\":null}"));
+    assert!(lines[2].starts_with("{\"argv\":[\"/no/such/command\"],"));
+    assert!(lines[2].contains(",\"pid\":0,"));
+    assert!(lines[2].contains(",\"error\":\"No such file"));
+    assert!(lines[3].starts_with("{\"argv\":[\"true\"],"));
+    assert!(lines[3].contains(",\"code\":null,"));
+    assert!(lines[3].ends_with(",\"error\":\"Dropped without wait\"}"));
+}
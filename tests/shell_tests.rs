@@ -16,9 +16,25 @@
 extern crate libc;
 extern crate env_logger;
 
+use std::env;
+use std::fs;
+use std::mem;
+use std::os::unix::process::ExitStatusExt;
+use std::panic;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::thread;
 use std::time::Duration;
+use std::time::Instant;
+use shell::DryRunResult;
+use shell::Pool;
 use shell::ShellResult;
+use shell::ShellResultExt;
+use shell::SignalTrap;
+use shell::TraceOutput;
+use shell::XTrace;
 use libc::c_int;
 
 fn setup() {
@@ -103,3 +119,658 @@ fn test_trap_signal_and_wait_children() {
         }
     }
 }
+
+#[test]
+fn test_signal_trap() {
+    setup();
+    let result = unsafe {
+        let result = libc::fork();
+        assert_ne!(result, -1);
+        result
+    };
+    if result == 0 {
+        let marker = format!("/tmp/shell-signal-trap-{}", process::id());
+        SignalTrap::new()
+            .signals(&[libc::SIGUSR1])
+            .forward(true)
+            .shutdown_timeout(Duration::from_millis(200))
+            .on_exit(move |signal| {
+                fs::write(marker, signal.to_string()).unwrap();
+            })
+            .install().unwrap();
+        // Ignores the forwarded signal, so it is killed after the timeout.
+        shell::spawn(|| {
+            cmd!("sh -c {}", "trap '' USR1; exec tail -f /dev/null").run()
+        });
+        thread::sleep(Duration::from_millis(100));
+        unsafe {
+            assert_eq!(libc::kill(libc::getpid(), libc::SIGUSR1), 0);
+        }
+        thread::sleep(Duration::from_secs(10));
+    } else {
+        let start = Instant::now();
+        let mut status: c_int = 0;
+        unsafe {
+            libc::waitpid(result, &mut status as *mut c_int, 0);
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 128 + libc::SIGUSR1);
+        let marker = format!("/tmp/shell-signal-trap-{}", result);
+        assert_eq!(fs::read_to_string(&marker).unwrap(),
+                   libc::SIGUSR1.to_string());
+        fs::remove_file(&marker).unwrap();
+    }
+}
+
+#[test]
+fn test_signal_trap_second_signal() {
+    setup();
+    let result = unsafe {
+        let result = libc::fork();
+        assert_ne!(result, -1);
+        result
+    };
+    if result == 0 {
+        SignalTrap::new().signals(&[libc::SIGUSR2]).install().unwrap();
+        // Ignores the forwarded signal, so only the second signal stops it.
+        shell::spawn(|| {
+            cmd!("sh -c {}", "trap '' USR2; exec tail -f /dev/null").run()
+        });
+        // Started between the signals on a shell registered after the first.
+        let marker = format!("/tmp/shell-signal-trap-late-{}", process::id());
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            cmd!("sh -c {}", &format!(
+                    "trap '' USR2; echo $$ > {}; exec tail -f /dev/null",
+                    marker)).run()
+        });
+        thread::sleep(Duration::from_millis(100));
+        for _ in 0..2 {
+            unsafe {
+                assert_eq!(libc::kill(libc::getpid(), libc::SIGUSR2), 0);
+            }
+            thread::sleep(Duration::from_millis(200));
+        }
+        thread::sleep(Duration::from_secs(10));
+    } else {
+        let start = Instant::now();
+        let mut status: c_int = 0;
+        unsafe {
+            libc::waitpid(result, &mut status as *mut c_int, 0);
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 128 + libc::SIGUSR2);
+        let marker = format!("/tmp/shell-signal-trap-late-{}", result);
+        let pid = fs::read_to_string(&marker).unwrap();
+        fs::remove_file(&marker).unwrap();
+        assert_exits(pid.trim());
+    }
+}
+
+#[test]
+fn test_signal_trap_during_scope() {
+    setup();
+    let result = unsafe {
+        let result = libc::fork();
+        assert_ne!(result, -1);
+        result
+    };
+    if result == 0 {
+        SignalTrap::new().signals(&[libc::SIGUSR1]).install().unwrap();
+        // Started on the outer shell before entering the scope.
+        let child = cmd!("tail -f /dev/null").spawn().unwrap();
+        let pid = child.process().read().unwrap().as_ref().unwrap().child.id();
+        let marker = format!("/tmp/shell-signal-trap-scope-{}", process::id());
+        fs::write(marker, pid.to_string()).unwrap();
+        shell::scope(|_| {
+            unsafe {
+                assert_eq!(libc::kill(libc::getpid(), libc::SIGUSR1), 0);
+            }
+            thread::sleep(Duration::from_secs(10));
+        });
+    } else {
+        let start = Instant::now();
+        let mut status: c_int = 0;
+        unsafe {
+            libc::waitpid(result, &mut status as *mut c_int, 0);
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 128 + libc::SIGUSR1);
+        let marker = format!("/tmp/shell-signal-trap-scope-{}", result);
+        let pid = fs::read_to_string(&marker).unwrap();
+        fs::remove_file(&marker).unwrap();
+        assert_exits(&pid);
+    }
+}
+
+/// Asserts that a process of a forked test exits, and is reaped by init if it
+/// is orphaned.
+fn assert_exits(pid: &str) {
+    let stat = format!("/proc/{}/stat", pid);
+    let deadline = Instant::now() + Duration::from_secs(1);
+    loop {
+        match fs::read_to_string(&stat) {
+            Ok(ref stat) if !stat.contains(") Z ") => {}
+            _ => break,
+        }
+        assert!(Instant::now() < deadline, "{} is still running", pid);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_signal_trap_from_terminal() {
+    setup();
+    let result = unsafe {
+        let result = libc::fork();
+        assert_ne!(result, -1);
+        result
+    };
+    if result == 0 {
+        let marker = format!("/tmp/shell-signal-trap-tty-{}", process::id());
+        SignalTrap::new()
+            .signals(&[libc::SIGUSR1])
+            .shutdown_timeout(Duration::from_millis(300))
+            .install().unwrap();
+        shell::spawn(move || {
+            cmd!("sh -c {}", &format!(
+                    "trap 'echo x >> {}' USR1; while :; do :; done", marker))
+                .run()
+        });
+        thread::sleep(Duration::from_millis(100));
+        // Sent by the kernel like Ctrl-C, which the terminal also sends to
+        // the child in the same process group.
+        unsafe {
+            let mut info = mem::zeroed::<libc::siginfo_t>();
+            info.si_signo = libc::SIGUSR1;
+            info.si_code = libc::SI_KERNEL;
+            assert_eq!(libc::syscall(
+                    libc::SYS_rt_sigqueueinfo, libc::getpid(), libc::SIGUSR1,
+                    &info as *const libc::siginfo_t), 0);
+        }
+        thread::sleep(Duration::from_secs(10));
+    } else {
+        let start = Instant::now();
+        let mut status: c_int = 0;
+        unsafe {
+            libc::waitpid(result, &mut status as *mut c_int, 0);
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 128 + libc::SIGUSR1);
+        // The child is killed after the timeout without the signal forwarded.
+        let marker = format!("/tmp/shell-signal-trap-tty-{}", result);
+        assert!(!Path::new(&marker).exists());
+    }
+}
+
+#[test]
+fn test_signal_trap_unblocks_children() {
+    setup();
+    let result = unsafe {
+        let result = libc::fork();
+        assert_ne!(result, -1);
+        result
+    };
+    if result == 0 {
+        SignalTrap::new().signals(&[libc::SIGUSR1]).install().unwrap();
+        // Killed by the signal only if it is not blocked as in this process.
+        let code = match cmd!("sh -c {}", "kill -USR1 $$").run() {
+            Err(ref error) if error.exit_status().and_then(
+                |status| status.signal()) == Some(libc::SIGUSR1) => 0,
+            _ => 1,
+        };
+        process::exit(code);
+    } else {
+        let mut status: c_int = 0;
+        unsafe {
+            libc::waitpid(result, &mut status as *mut c_int, 0);
+        }
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+}
+
+#[test]
+fn test_die_with_parent() {
+    setup();
+    // PR_SET_PDEATHSIG fires when the spawning thread exits.
+    let pid = thread::spawn(|| {
+        let child = cmd!("sleep 10").die_with_parent().spawn().unwrap();
+        let lock = child.process().read().unwrap();
+        lock.as_ref().unwrap().child.id() as libc::pid_t
+    }).join().unwrap();
+    unsafe {
+        let mut status: c_int = 0;
+        assert_eq!(libc::waitpid(pid, &mut status as *mut c_int, 0), pid);
+        assert!(libc::WIFSIGNALED(status));
+        assert_eq!(libc::WTERMSIG(status), libc::SIGKILL);
+    }
+}
+
+#[test]
+fn test_resource_usage() {
+    setup();
+    let exit = cmd!("sleep 0.1").collect_usage().run().unwrap();
+    let usage = exit.usage().unwrap();
+    assert!(usage.wall_time >= Duration::from_millis(100));
+    assert!(usage.max_rss > 0);
+    assert!(shell::ok().unwrap().usage().is_none());
+    assert!(cmd!("true").run().unwrap().usage().is_none());
+
+    // Failed commands carry the usage too.
+    let result = cmd!("sh -c {}", "sleep 0.1; exit 3").collect_usage().run();
+    assert_eq!(result.code(), 3);
+    let error = match result {
+        Ok(_) => panic!("should fail"),
+        Err(error) => error,
+    };
+    assert!(error.usage().unwrap().wall_time >= Duration::from_millis(100));
+    assert_eq!(error.exit_status().and_then(|status| status.code()), Some(3));
+}
+
+#[test]
+fn test_output_line_callbacks() {
+    setup();
+    let stdout = Arc::new(Mutex::new(Vec::new()));
+    let stderr = Arc::new(Mutex::new(Vec::new()));
+    let stdout_clone = stdout.clone();
+    let stderr_clone = stderr.clone();
+    cmd!("sh -c {}", "echo a; echo b >&2; echo c")
+        .on_stdout_line(move |line| {
+            stdout_clone.lock().unwrap().push(line.to_string())
+        })
+        .on_stderr_line(move |line| {
+            stderr_clone.lock().unwrap().push(line.to_string())
+        })
+        .run().unwrap();
+    assert_eq!(*stdout.lock().unwrap(), vec!["a", "c"]);
+    assert_eq!(*stderr.lock().unwrap(), vec!["b"]);
+}
+
+#[test]
+fn test_signal_while_joining_readers() {
+    setup();
+    // The background process keeps stdout open after sh exits.
+    let job = shell::spawn(|| {
+        cmd!("sh -c {}", "timeout 1 tail -f /dev/null & echo a")
+            .on_stdout_line(|_| ()).run()
+    });
+    thread::sleep(Duration::from_millis(200));
+    let start = Instant::now();
+    job.signal(libc::SIGTERM);
+    assert!(job.running_processes().is_empty());
+    assert!(start.elapsed() < Duration::from_millis(500));
+    assert!(job.join().unwrap().is_ok());
+
+    let job = shell::spawn(|| cmd!("tail -f /dev/null").stdout_utf8());
+    thread::sleep(Duration::from_millis(100));
+    job.signal(libc::SIGTERM);
+    assert!(job.join().unwrap().is_err());
+}
+
+#[test]
+fn test_lines() {
+    setup();
+    let lines = cmd!("seq 3").lines().unwrap()
+        .collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(lines, vec!["1", "2", "3"]);
+
+    let mut lines = cmd!("sh -c {}", "echo a; exit 1").lines().unwrap();
+    assert_eq!(lines.next().unwrap().unwrap(), "a");
+    assert!(lines.next().unwrap().is_err());
+    assert!(lines.next().is_none());
+}
+
+#[test]
+fn test_tee() {
+    setup();
+    let buffer = shell::TeeBuffer::new();
+    let path = env::temp_dir().join(
+        format!("shell_test_tee_{}", process::id()));
+    cmd!("sh -c {}", "echo a; echo b >&2; echo c")
+        .tee_stdout(buffer.clone())
+        .tee_stderr(path.as_path())
+        .tee_prefix("[test] ")
+        .run().unwrap();
+    assert_eq!(buffer.to_string_lossy(), "[test] a\n[test] c\n");
+    assert_eq!(fs::read_to_string(&path).unwrap(), "[test] b\n");
+    fs::remove_file(&path).unwrap();
+
+    // Bytes are kept as written, and the prefix starts each line.
+    let buffer = shell::TeeBuffer::new();
+    cmd!("printf {}", "\\377\\nabc").tee_stdout(buffer.clone())
+        .on_stdout_line(|_| ()).run().unwrap();
+    assert_eq!(buffer.contents(), b"\xff\nabc");
+    let buffer = shell::TeeBuffer::new();
+    cmd!("printf {}", "a\\nb").tee_stdout(buffer.clone()).tee_prefix("> ")
+        .run().unwrap();
+    assert_eq!(buffer.contents(), b"> a\n> b");
+
+    // stdout is consumed by tee, callbacks or capture.
+    assert!(cmd!("echo a").tee_stdout(buffer.clone()).stdout_utf8().is_err());
+    assert!(cmd!("echo a").on_stdout_line(|_| ()).stdout_utf8().is_err());
+    assert!(cmd!("echo a").capture_output().lines().is_err());
+    let child = cmd!("echo a").stdout(shell::StdioSpec::Piped)
+        .tee_stdout(buffer.clone()).spawn().unwrap();
+    assert!(child.stdout_utf8().is_err());
+}
+
+#[test]
+fn test_capture_output() {
+    setup();
+    let script = "echo a; sleep 0.1; echo b >&2; sleep 0.1; echo c";
+    let exit = cmd!("sh -c {}", script).capture_output().run().unwrap();
+    let transcript = exit.transcript().unwrap();
+    assert_eq!(transcript.to_string_lossy(), "a\nb\nc\n");
+    assert_eq!(transcript.stdout(), b"a\nc\n");
+    assert_eq!(transcript.stderr(), b"b\n");
+    assert_eq!(transcript.chunks[1].stream, shell::Stream::Stderr);
+
+    let result = cmd!("sh -c {}", "echo error >&2; exit 3")
+        .capture_output().run();
+    assert_eq!(result.as_ref().err().unwrap().transcript().unwrap().stderr(),
+               b"error\n");
+    assert_eq!(result.code(), 3);
+}
+
+#[test]
+fn test_retry() {
+    setup();
+    let path = env::temp_dir().join(
+        format!("shell_test_retry_{}", process::id()));
+    let script = format!(
+        "n=$(cat {0} 2>/dev/null || echo 0); echo $((n + 1)) > {0}; \
+         test $n -ge 2", path.display());
+    let policy = shell::RetryPolicy {
+        attempts: 3,
+        backoff: Duration::from_millis(10),
+        ..Default::default()
+    };
+    assert!(cmd!("sh -c {}", &script).retry(policy.clone()).run().is_ok());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "3\n");
+    fs::remove_file(&path).unwrap();
+
+    let policy = shell::RetryPolicy { attempts: 2, ..policy };
+    assert!(cmd!("sh -c {}", &script).retry(policy).run().is_err());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "2\n");
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_retry_stops_when_signaled() {
+    setup();
+    let started = Instant::now();
+    let job = shell::spawn(|| -> ShellResult {
+        cmd!("false").retry(shell::RetryPolicy {
+            attempts: 100,
+            backoff: Duration::from_millis(100),
+            ..Default::default()
+        }).run()
+    });
+    thread::sleep(Duration::from_millis(50));
+    job.signal(libc::SIGTERM);
+    assert!(job.join().unwrap().is_err());
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_dry_run() {
+    shell::spawn(|| {
+        shell::set_dry_run(Some(DryRunResult::new(1, "a\nb\n")));
+        assert!(cmd!("touch /dry-run-must-not-exist").run().is_err());
+        assert!(fs::metadata("/dry-run-must-not-exist").is_err());
+        let lines = cmd!("seq 3").lines().unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[2].is_err());
+        assert!(cmd!("false").ok_codes(&[1]).run().is_ok());
+        shell::spawn(|| {
+            assert!(cmd!("touch /dry-run-must-not-exist").run().is_err());
+        }).join().unwrap();
+        assert!(fs::metadata("/dry-run-must-not-exist").is_err());
+
+        shell::set_dry_run(None);
+        assert_eq!(cmd!("echo real").stdout_utf8().unwrap(), "real\n");
+    }).join().unwrap();
+}
+
+#[test]
+fn test_xtrace_inherited_by_spawn() {
+    shell::spawn(|| {
+        let xtrace = XTrace {
+            prefix: String::from("[trace] "),
+            output: TraceOutput::Log(String::from("shell_tests")),
+        };
+        shell::set_xtrace(Some(xtrace.clone()));
+        shell::spawn(move || {
+            assert_eq!(shell::xtrace(), Some(xtrace));
+            cmd!("true").run().unwrap();
+        }).join().unwrap();
+        shell::set_xtrace(None);
+        shell::spawn(|| assert_eq!(shell::xtrace(), None)).join().unwrap();
+    }).join().unwrap();
+}
+
+#[test]
+fn test_signal_nested_spawn() {
+    setup();
+    let job = shell::spawn(|| -> ShellResult {
+        let inner = shell::spawn(|| -> ShellResult {
+            cmd!("sleep 5").run()
+        });
+        let result = inner.join().unwrap();
+        assert!(result.is_err());
+        // Threads started after the signal inherit the signaled state.
+        shell::spawn(|| cmd!("true").run()).join().unwrap()
+    });
+    thread::sleep(Duration::from_millis(100));
+    let start = Instant::now();
+    job.signal(libc::SIGTERM);
+    assert!(job.join().unwrap().is_err());
+    assert!(start.elapsed() < Duration::from_secs(3));
+}
+
+#[test]
+fn test_cd_and_export_per_thread() {
+    setup();
+    let jobs = ["/", "/tmp"].iter().map(|dir| {
+        shell::spawn(move || -> Result<(String, String), shell::ShellError> {
+            shell::cd(dir)?;
+            shell::export("SHELL_TEST_DIR", dir);
+            let pwd = cmd!("pwd").stdout_utf8()?;
+            let var = cmd!("echo $SHELL_TEST_DIR").stdout_utf8()?;
+            Ok((pwd, var))
+        })
+    }).collect::<Vec<_>>();
+    let results = jobs.into_iter().map(|job| job.join().unwrap().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(results[0], ("/\n".to_string(), "/\n".to_string()));
+    assert_eq!(results[1], ("/tmp\n".to_string(), "/tmp\n".to_string()));
+    assert!(shell::var("SHELL_TEST_DIR").is_err());
+}
+
+#[test]
+fn test_pushd_and_unset() {
+    setup();
+    shell::spawn(|| {
+        shell::cd("/").unwrap();
+        {
+            let _guard = shell::pushd("tmp").unwrap();
+            assert_eq!(cmd!("pwd").stdout_utf8().unwrap(), "/tmp\n");
+            // Inherited by nested threads.
+            shell::spawn(|| {
+                assert_eq!(cmd!("pwd").stdout_utf8().unwrap(), "/tmp\n");
+            }).join().unwrap();
+        }
+        assert_eq!(cmd!("pwd").stdout_utf8().unwrap(), "/\n");
+        assert!(shell::cd("/no-such-dir").is_err());
+
+        env::set_var("SHELL_TEST_UNSET", "set");
+        shell::unset("SHELL_TEST_UNSET");
+        assert_eq!(cmd!("sh -c {}", "echo ${SHELL_TEST_UNSET-unset}")
+                   .stdout_utf8().unwrap(), "unset\n");
+        assert!(shell::var("SHELL_TEST_UNSET").is_err());
+    }).join().unwrap();
+}
+
+#[test]
+fn test_scope_borrows_and_cleans_up() {
+    setup();
+    let words = ["a", "b"];
+    let sleep_result = Mutex::new(None);
+    let start = Instant::now();
+    let results = shell::scope(|s| {
+        let handles = words.iter().map(|word| {
+            s.spawn(move || cmd!("echo {}", word).stdout_utf8().unwrap())
+        }).collect::<Vec<_>>();
+        // Running commands are joined rather than killed.
+        s.spawn(|| {
+            *sleep_result.lock().unwrap() = Some(
+                cmd!("timeout 0.2 tail -f /dev/null").ok_codes(&[124]).run());
+        });
+        // Processes left without wait are killed.
+        let leftover = s.spawn(|| {
+            let child = cmd!("tail -f /dev/null").spawn().unwrap();
+            let lock = child.process().read().unwrap();
+            lock.as_ref().unwrap().child.id()
+        });
+        let results = handles.into_iter().map(|h| h.join().unwrap())
+            .collect::<Vec<_>>();
+        (results, leftover.join().unwrap())
+    });
+    let (results, leftover) = results;
+    assert!(!Path::new(&format!("/proc/{}", leftover)).exists());
+    assert_eq!(results, vec!["a\n", "b\n"]);
+    assert!(sleep_result.lock().unwrap().take().unwrap().is_ok());
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_scope_cleans_up_on_panic() {
+    setup();
+    let start = Instant::now();
+    let result = panic::catch_unwind(|| {
+        shell::scope(|_| {
+            let _child = cmd!("sleep 10").spawn().unwrap();
+            panic!("Scope panics");
+        })
+    });
+    assert!(result.is_err());
+    assert!(start.elapsed() < Duration::from_secs(5));
+    // The current thread is back to its own shell.
+    assert!(cmd!("true").run().is_ok());
+}
+
+#[test]
+fn test_cancellation_token() {
+    setup();
+    let job = shell::spawn(|| -> Result<(), shell::ShellError> {
+        loop {
+            shell::check_cancelled()?;
+            thread::sleep(Duration::from_millis(10));
+        }
+    });
+    let token = job.cancellation_token();
+    assert!(!token.is_cancelled());
+    let waiter = thread::spawn(move || token.wait_timeout(Duration::from_secs(5)));
+    thread::sleep(Duration::from_millis(50));
+    job.signal(libc::SIGTERM);
+    match job.join().unwrap() {
+        Err(shell::ShellError::Cancelled) => {}
+        result => panic!("Unexpected result {:?}", result),
+    }
+    assert!(waiter.join().unwrap());
+}
+
+#[test]
+fn test_join_timeout_and_running_processes() {
+    setup();
+    let job = shell::spawn(|| -> ShellResult {
+        shell::spawn(|| cmd!("sleep 10").run()).join().unwrap()
+    });
+    thread::sleep(Duration::from_millis(100));
+    assert!(!job.is_finished());
+    let processes = job.running_processes();
+    assert_eq!(processes.len(), 1);
+    assert_eq!(processes[0].command_line, "sleep 10");
+    assert!(processes[0].pid > 0);
+
+    let job = job.join_timeout(Duration::from_millis(50)).err().unwrap();
+    job.signal(libc::SIGTERM);
+    let result = job.join_timeout(Duration::from_secs(5)).ok().unwrap();
+    assert!(result.unwrap().is_err());
+}
+
+#[test]
+fn test_parallel() {
+    setup();
+    let start = Instant::now();
+    let results = shell::parallel(
+        4, (0..4).map(|i| cmd!("sh -c {}", &format!("sleep 0.3; exit {}", i))));
+    assert!(start.elapsed() < Duration::from_secs(1));
+    let codes = results.iter().map(|result| result.code()).collect::<Vec<_>>();
+    assert_eq!(codes, vec![0, 1, 2, 3]);
+}
+
+#[test]
+fn test_pool_fail_fast() {
+    setup();
+    let start = Instant::now();
+    let results = Pool::new(2).fail_fast(true).run(vec![
+        cmd!("sleep 5"),
+        cmd!("sh -c {}", "sleep 0.1; exit 1"),
+        cmd!("true"),
+    ]);
+    assert!(start.elapsed() < Duration::from_secs(3));
+    assert!(results[0].is_err());
+    assert_eq!(results[1].code(), 1);
+    match results[2] {
+        Err(shell::ShellError::Cancelled) => {}
+        _ => panic!("The last command should be skipped"),
+    }
+}
+
+#[test]
+fn test_pool_keep_going_and_cancel() {
+    setup();
+    let results = Pool::new(1).run(vec![cmd!("false"), cmd!("true")]);
+    assert!(results[0].is_err());
+    assert!(results[1].is_ok());
+
+    let handle = Pool::new(2).spawn((0..4).map(|_| cmd!("sleep 5")));
+    thread::sleep(Duration::from_millis(100));
+    assert!(!handle.is_finished());
+    handle.cancel();
+    let results = handle.join();
+    assert_eq!(results.len(), 4);
+    assert!(results.iter().all(|result| result.is_err()));
+}
+
+#[test]
+fn test_xargs() {
+    setup();
+    let args = (0..200000).map(|i| format!("argument-{:010}", i))
+        .collect::<Vec<_>>();
+    for parallel in &[false, true] {
+        let counts = Arc::new(Mutex::new(Vec::new()));
+        let counts_clone = counts.clone();
+        let command = cmd!("sh -c {} sh", "echo $#").on_stdout_line(
+            move |line| counts_clone.lock().unwrap().push(
+                line.parse::<usize>().unwrap()));
+        let result = if *parallel {
+            command.xargs_parallel(&args, 4)
+        } else {
+            command.xargs(&args)
+        };
+        assert!(result.is_ok());
+        let counts = counts.lock().unwrap();
+        assert!(counts.len() > 1);
+        assert_eq!(counts.iter().sum::<usize>(), args.len());
+    }
+    assert!(cmd!("false").xargs(["a"]).is_err());
+    assert!(cmd!("false").xargs(Vec::<String>::new()).is_ok());
+}
@@ -0,0 +1,57 @@
+// Copyright 2017 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Becoming a subreaper affects the whole process, so the tests live in their
+//! own binary.
+
+#[macro_use] extern crate shell;
+extern crate libc;
+
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+fn spawn_orphan(seconds: &str) -> String {
+    // The parent exits right after starting the grandchild.
+    let script = format!("sleep {} > /dev/null & echo $!", seconds);
+    let pid = cmd!("sh -c {}", &script).stdout_utf8().unwrap();
+    format!("/proc/{}", pid.trim())
+}
+
+#[test]
+fn test_subreaper() {
+    shell::become_subreaper().unwrap();
+
+    // Children spawned by ShellCommand are left to their owner.
+    let child = cmd!("true").spawn().unwrap();
+    thread::sleep(Duration::from_millis(300));
+    assert!(child.wait().is_ok());
+
+    // Orphan exits by itself and is reaped by the background thread.
+    let orphan = spawn_orphan("0.5");
+    for _ in 0..50 {
+        if !Path::new(&orphan).exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(!Path::new(&orphan).exists());
+
+    // Orphan is killed and waited explicitly.
+    let orphan = spawn_orphan("10");
+    assert!(Path::new(&orphan).exists());
+    shell::kill_descendants(libc::SIGKILL);
+    shell::wait_descendants().unwrap();
+    assert!(!Path::new(&orphan).exists());
+}